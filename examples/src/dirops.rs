@@ -73,9 +73,12 @@ fn main() -> ! {
         },
     };
 
-    match fat32::Mbr::read_part_info(&sdcard) {
-        Ok(part_info) => {
-            match fat32::Volume::open_volume(&sdcard, 0, &part_info[0]) {
+    let cache = fat32::SectorCache::<2>::new();
+    let partition = fat32::PartitionTable::read(&sdcard, &cache)
+        .and_then(|table| table.partition(&sdcard, &cache, 0)?.ok_or(fat32::FatError::BadPartitionNumber));
+    match partition {
+        Ok(partition) => {
+            match fat32::Volume::open_volume(&sdcard, cache, 0, &partition) {
                 Ok(mut vol) => {
                     pm_write!(serial, "Trying to read ").void_unwrap();
                     for c in FILENAME {
@@ -112,7 +115,7 @@ fn main() -> ! {
             };
         },
         Err(e) => {
-            pm_write!(serial, "Couldn't read MBR: {}\n", e as u8).unwrap();
+            pm_write!(serial, "Couldn't read partition table: {}\n", e as u8).unwrap();
             panic!("");
         },
     }
@@ -92,27 +92,50 @@ fn main() -> ! {
         },
     }
 
-    pm_write!(serial, "\nMaster Boot Record:\n").void_unwrap();
-    match fat32::Mbr::read_part_info(&sdcard) {
-        Ok(part_info) => {
-            for (i, part_info) in part_info.iter().enumerate() {
-                pm_write!(serial, "  Partition {}", i).void_unwrap();
-                uwrite!(serial, "{:?}", part_info).void_unwrap();
+    // MMC cards don't have SCR or SD Status registers, so don't bail out if these fail
+    pm_write!(serial, "\nSD configuration:\n").void_unwrap();
+    match sdcard.borrow_mut().read_scr() {
+        Ok(scr) => uwrite!(serial, "{:?}", scr).void_unwrap(),
+        Err(e) => pm_write!(serial, "couldn't read SCR register: {}\n", e as u8).void_unwrap(),
+    }
+    pm_write!(serial, "\nSD status:\n").void_unwrap();
+    match sdcard.borrow_mut().read_sd_status() {
+        Ok(status) => uwrite!(serial, "{:?}", status).void_unwrap(),
+        Err(e) => pm_write!(serial, "couldn't read SD Status register: {}\n", e as u8).void_unwrap(),
+    }
+
+    pm_write!(serial, "\nPartition table:\n").void_unwrap();
+    let cache = fat32::SectorCache::<2>::new();
+    match fat32::PartitionTable::read(&sdcard, &cache) {
+        Ok(table) => {
+            let mut first = None;
+            for i in 0..table.len() {
+                match table.partition(&sdcard, &cache, i) {
+                    Ok(Some(partition)) => {
+                        pm_write!(serial, "  Partition {}", i).void_unwrap();
+                        uwrite!(serial, "{:?}", partition).void_unwrap();
+                        first = first.or(Some(partition));
+                    },
+                    Ok(None) => {},
+                    Err(e) => pm_write!(serial, "  Partition {}: couldn't read entry: {}\n", i, e as u8).void_unwrap(),
+                }
             }
 
-            pm_write!(serial, "\nPartition 0:\n").void_unwrap();
-            match fat32::Volume::open_volume(&sdcard, 0, &part_info[0]) {
-                Ok(vol) => {
-                    uwrite!(serial, "{:?}", vol.partition).void_unwrap();
-                },
-                Err(e) => {
-                    pm_write!(serial, "Couldn't read volume: {}\n", e as u8).void_unwrap();
-                    panic!("");
-                },
-            };
+            if let Some(partition) = first {
+                pm_write!(serial, "\nFirst partition:\n").void_unwrap();
+                match fat32::Volume::open_volume(&sdcard, cache, 0, &partition) {
+                    Ok(vol) => {
+                        uwrite!(serial, "{:?}", vol.partition).void_unwrap();
+                    },
+                    Err(e) => {
+                        pm_write!(serial, "Couldn't read volume: {}\n", e as u8).void_unwrap();
+                        panic!("");
+                    },
+                };
+            }
         },
         Err(e) => {
-            pm_write!(serial, "Couldn't read MBR: {}\n", e as u8).unwrap();
+            pm_write!(serial, "Couldn't read partition table: {}\n", e as u8).unwrap();
             panic!("");
         },
     }
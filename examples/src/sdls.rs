@@ -107,25 +107,17 @@ fn main() -> ! {
         },
     };
 
-    match fat32::Mbr::read_part_info(&sdcard) {
-        Ok(part_info) => {
-            match fat32::Volume::open_volume(&sdcard, 0, &part_info[0]) {
-                Ok(vol) => {
-                    pm_write!(serial, "volume opened!  Contents:\n").void_unwrap();
-                    let mut root = vol.open_root(O_RDONLY);
-                    if let Err(e) = vol.ls(&sdcard, &mut root, false, 0, RECURSION_DEPTH, &mut serial, print_entry) {
-                        pm_write!(serial, "Couldn't read directory: {}\n", e as u8).void_unwrap();
-                        panic!("");
-                    }
-                },
-                Err(e) => {
-                    pm_write!(serial, "Couldn't read volume: {}\n", e as u8).void_unwrap();
-                    panic!("");
-                },
-            };
+    match fat32::Volume::<2>::mount_first_fat(&sdcard, 0) {
+        Ok(vol) => {
+            pm_write!(serial, "volume opened!  Contents:\n").void_unwrap();
+            let mut root = vol.open_root(O_RDONLY);
+            if let Err(e) = vol.ls(&sdcard, &mut root, false, 0, RECURSION_DEPTH, &mut serial, print_entry) {
+                pm_write!(serial, "Couldn't read directory: {}\n", e as u8).void_unwrap();
+                panic!("");
+            }
         },
         Err(e) => {
-            pm_write!(serial, "Couldn't read MBR: {}\n", e as u8).unwrap();
+            pm_write!(serial, "Couldn't find a FAT32 volume: {}\n", e as u8).void_unwrap();
             panic!("");
         },
     }
@@ -1,4 +1,3 @@
-#![feature(extend_one)]
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
@@ -58,8 +57,9 @@ pub fn progmem_str(input: TokenStream) -> TokenStream {
     };
     let the_string_len: usize = the_string_bytes.len();
 
+    // Only AVR has a separate program memory; elsewhere this is just a regular static
     let output = quote! {
-        #[link_section = ".progmem.data"]
+        #[cfg_attr(target_arch = "avr", link_section = ".progmem.data")]
         static #name: [u8; #the_string_len] = #the_string_array_string;
     };
 
@@ -98,11 +98,13 @@ pub fn pm_write(input: TokenStream) -> TokenStream {
         } else {
             let ident = format_ident!("STR_{}", Uuid::new_v4().to_simple().to_string());
             var_defs.push(quote! {
+                #[cfg(target_arch = "avr")]
                 progmem_str! {
                     static progmem #ident: &'static str = #chunk;
                 }
             });
             wexprs.push(quote! {
+                #[cfg(target_arch = "avr")]
                 for i in 0..#chunk_len {
                     let p_addr: *const u8 = core::ptr::addr_of!(#ident[i]);
                     let res: u8;
@@ -111,6 +113,8 @@ pub fn pm_write(input: TokenStream) -> TokenStream {
                     }
                     f.write_char(res as char)?;
                 }
+                #[cfg(not(target_arch = "avr"))]
+                f.write_str(#chunk)?;
             });
         }
         if let Some(a) = args_iter.next() {
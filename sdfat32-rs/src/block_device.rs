@@ -0,0 +1,34 @@
+use core::cell::RefCell;
+
+
+// Anything that can store fixed-size (BLOCK_SIZE) sectors can back a FAT32 volume: an SD card, a
+// RAM disk, a flash chip, or a disk image on a host machine.
+pub trait BlockDevice {
+    type Error;
+
+    // Read `dest.len() / BLOCK_SIZE` consecutive sectors into `dest`, starting at `start_sector`;
+    // the buffer length must be a multiple of BLOCK_SIZE
+    fn read_sectors(&mut self, start_sector: u32, dest: &mut [u8]) -> Result<(), Self::Error>;
+
+    // Write `src.len() / BLOCK_SIZE` consecutive sectors from `src`, starting at `start_sector`;
+    // the buffer length must be a multiple of BLOCK_SIZE
+    fn write_sectors(&mut self, start_sector: u32, src: &[u8]) -> Result<(), Self::Error>;
+
+    // Tell the device that `sector_count` sectors starting at `start_sector` are no longer in
+    // use, so it can erase them ahead of time; their contents are undefined afterwards.  Devices
+    // that can't do anything useful with this don't have to implement it.
+    fn erase_sectors(&mut self, _start_sector: u32, _sector_count: u32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn sector_count(&mut self) -> Result<u32, Self::Error>;
+
+    // Number of sectors the device erases or programs as a unit (an SD card's allocation unit);
+    // partitions and filesystem structures are lined up on these boundaries where possible.
+    // Devices without one can leave it at a single sector.
+    fn erase_block_sectors(&mut self) -> Result<u32, Self::Error> {
+        Ok(1)
+    }
+}
+
+pub type BlockDeviceRef<'d, D> = &'d RefCell<D>;
@@ -0,0 +1,404 @@
+use super::FatError;
+use crate::{
+    block_device::BlockDevice,
+    sdcard::BLOCK_SIZE,
+};
+use core::{
+    cell::{
+        Cell,
+        UnsafeCell,
+    },
+    marker::PhantomData,
+};
+
+
+// A slot in Read mode matches what's on the device; a slot in Write mode has been modified and
+// must be written back before it's reused for another sector
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub(crate) enum DataMode {
+    Idle,
+    Locked,
+    Read,
+    Write,
+}
+
+struct Slot {
+    data: UnsafeCell<[u8; BLOCK_SIZE]>,
+    sector: Cell<u32>,
+    mode: Cell<DataMode>,
+    last_used: Cell<u32>,
+}
+
+// An N-way cache of device sectors with least-recently-used replacement.  Each slot costs
+// BLOCK_SIZE bytes of RAM, so small boards will want one or two slots; bigger boards can afford
+// to keep directory, FAT and data sectors around at the same time.  The same cache is used to
+// read the partition table and then handed to the volume, so there's only ever one set of
+// buffers; it mustn't be shared between devices.
+pub struct SectorCache<const N: usize> {
+    slots: [Slot; N],
+    clock: Cell<u32>,
+}
+
+// A sector that's been loaded into the cache; its slot can't be evicted until the block is
+// dropped
+pub(crate) struct Block<'c, T> {
+    slot: &'c Slot,
+    old_mode: DataMode,
+    object: PhantomData<T>,
+}
+
+impl<'c, T> Block<'c, T> {
+    fn new(slot: &'c Slot) -> Block<'c, T> {
+        let old_mode = slot.mode.replace(DataMode::Locked);
+        Block { slot, old_mode, object: PhantomData }
+    }
+
+    pub(crate) fn get(&self) -> &T {
+        unsafe { &*(self.slot.data.get() as *const T) }
+    }
+
+    // Mark the slot as dirty; it will be written back to the device when it's evicted or when
+    // the cache is synced
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.old_mode = DataMode::Write;
+        unsafe { &mut *(self.slot.data.get() as *mut T) }
+    }
+}
+
+impl<T> Drop for Block<'_, T> {
+    fn drop(&mut self) {
+        self.slot.mode.set(self.old_mode);
+    }
+}
+
+impl Slot {
+    fn new() -> Slot {
+        Slot {
+            data: UnsafeCell::new([0; BLOCK_SIZE]),
+            sector: Cell::new(0),
+            mode: Cell::new(DataMode::Idle),
+            last_used: Cell::new(0),
+        }
+    }
+
+    #[inline(always)]
+    fn is_locked(&self) -> bool {
+        self.mode.get() == DataMode::Locked
+    }
+
+    #[inline(always)]
+    fn is_dirty(&self) -> bool {
+        self.mode.get() == DataMode::Write
+    }
+
+    #[inline(always)]
+    fn holds(&self, sector: u32) -> bool {
+        self.mode.get() != DataMode::Idle && self.sector.get() == sector
+    }
+
+    // Empty slots get used first, then the least recently used one
+    #[inline(always)]
+    fn eviction_rank(&self) -> u32 {
+        match self.mode.get() {
+            DataMode::Idle => 0,
+            _ => self.last_used.get().saturating_add(1),
+        }
+    }
+
+    #[inline(always)]
+    fn in_range(&self, start_sector: u32, end_sector: u32) -> bool {
+        self.mode.get() != DataMode::Idle && start_sector <= self.sector.get() && self.sector.get() < end_sector
+    }
+
+    fn write_back<D: BlockDevice>(&self, device: &mut D) -> Result<(), FatError> {
+        if device.write_sectors(self.sector.get(), unsafe { &*self.data.get() }).is_err() {
+            return Err(FatError::BlockDeviceFailed);
+        }
+        self.mode.set(DataMode::Read);
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for SectorCache<N> {
+    fn default() -> SectorCache<N> {
+        SectorCache::new()
+    }
+}
+
+impl<const N: usize> SectorCache<N> {
+    pub fn new() -> SectorCache<N> {
+        SectorCache {
+            slots: [(); N].map(|_| Slot::new()),
+            clock: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn read_sector_as<D: BlockDevice, T>(
+        &self,
+        device: &mut D,
+        sector: u32,
+    ) -> Result<Block<'_, T>, FatError> {
+        let slot = match self.slots.iter().find(|s| s.holds(sector)) {
+            Some(s) if s.is_locked() => return Err(FatError::DataBufferLocked),
+            Some(s) => s,
+            None => {
+                let s = match self.slots.iter().filter(|s| !s.is_locked()).min_by_key(|s| s.eviction_rank()) {
+                    Some(s) => s,
+                    None => return Err(FatError::DataBufferLocked),
+                };
+                if s.is_dirty() {
+                    s.write_back(device)?;
+                }
+
+                // Invalidate the slot first so a failed read doesn't leave stale data marked valid
+                s.mode.set(DataMode::Idle);
+                if device.read_sectors(sector, unsafe { &mut *s.data.get() }).is_err() {
+                    return Err(FatError::BlockDeviceFailed);
+                }
+                s.mode.set(DataMode::Read);
+                s.sector.set(sector);
+                s
+            },
+        };
+
+        let now = self.clock.get().wrapping_add(1);
+        self.clock.set(now);
+        slot.last_used.set(now);
+        Ok(Block::new(slot))
+    }
+
+    pub(crate) fn sync<D: BlockDevice>(&self, device: &mut D) -> Result<(), FatError> {
+        for slot in self.slots.iter() {
+            if slot.is_locked() {
+                return Err(FatError::DataBufferLocked);
+            } else if slot.is_dirty() {
+                slot.write_back(device)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write back one sector if the cache has a modified copy of it
+    pub(crate) fn sync_sector<D: BlockDevice>(&self, device: &mut D, sector: u32) -> Result<(), FatError> {
+        for slot in self.slots.iter() {
+            if slot.is_locked() && slot.holds(sector) {
+                return Err(FatError::DataBufferLocked);
+            } else if slot.is_dirty() && slot.holds(sector) {
+                slot.write_back(device)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Read sectors straight into `dest`, bypassing the cache; any modified copies of those
+    // sectors in the cache are written back first so the device has the latest data
+    pub(crate) fn read_sectors<D: BlockDevice>(
+        &self,
+        device: &mut D,
+        start_sector: u32,
+        dest: &mut [u8],
+    ) -> Result<(), FatError> {
+        let end_sector = start_sector + (dest.len() / BLOCK_SIZE) as u32;
+        for slot in self.slots.iter() {
+            if slot.is_dirty() && slot.in_range(start_sector, end_sector) {
+                slot.write_back(device)?;
+            }
+        }
+        if device.read_sectors(start_sector, dest).is_err() {
+            return Err(FatError::BlockDeviceFailed);
+        }
+        Ok(())
+    }
+
+    // Write sectors straight from `src`, bypassing the cache; any cached copies of those sectors
+    // are dropped since they're about to be out of date
+    pub(crate) fn write_sectors<D: BlockDevice>(
+        &self,
+        device: &mut D,
+        start_sector: u32,
+        src: &[u8],
+    ) -> Result<(), FatError> {
+        self.invalidate(start_sector, start_sector + (src.len() / BLOCK_SIZE) as u32)?;
+        if device.write_sectors(start_sector, src).is_err() {
+            return Err(FatError::BlockDeviceFailed);
+        }
+        Ok(())
+    }
+
+    // Let the device know it can throw away the contents of these sectors; any cached copies are
+    // dropped too, since the sectors no longer hold anything meaningful
+    pub(crate) fn erase_sectors<D: BlockDevice>(
+        &self,
+        device: &mut D,
+        start_sector: u32,
+        sector_count: u32,
+    ) -> Result<(), FatError> {
+        self.invalidate(start_sector, start_sector + sector_count)?;
+        if device.erase_sectors(start_sector, sector_count).is_err() {
+            return Err(FatError::BlockDeviceFailed);
+        }
+        Ok(())
+    }
+
+    fn invalidate(&self, start_sector: u32, end_sector: u32) -> Result<(), FatError> {
+        for slot in self.slots.iter() {
+            if slot.in_range(start_sector, end_sector) {
+                if slot.is_locked() {
+                    return Err(FatError::DataBufferLocked);
+                }
+                slot.mode.set(DataMode::Idle);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SECTORS: usize = 8;
+
+    // Keeps track of which sectors were read and written, in order
+    struct TestDevice {
+        sectors: [[u8; BLOCK_SIZE]; TEST_SECTORS],
+        reads: [u32; 16],
+        read_count: usize,
+        writes: [u32; 16],
+        write_count: usize,
+    }
+
+    impl TestDevice {
+        fn new() -> TestDevice {
+            let mut sectors = [[0; BLOCK_SIZE]; TEST_SECTORS];
+            for (i, sector) in sectors.iter_mut().enumerate() {
+                sector[0] = i as u8;
+            }
+            TestDevice {
+                sectors,
+                reads: [0; 16],
+                read_count: 0,
+                writes: [0; 16],
+                write_count: 0,
+            }
+        }
+
+        fn reads(&self) -> &[u32] {
+            &self.reads[..self.read_count]
+        }
+
+        fn writes(&self) -> &[u32] {
+            &self.writes[..self.write_count]
+        }
+    }
+
+    impl BlockDevice for TestDevice {
+        type Error = ();
+
+        fn read_sectors(&mut self, start_sector: u32, dest: &mut [u8]) -> Result<(), ()> {
+            dest.copy_from_slice(&self.sectors[start_sector as usize]);
+            self.reads[self.read_count] = start_sector;
+            self.read_count += 1;
+            Ok(())
+        }
+
+        fn write_sectors(&mut self, start_sector: u32, src: &[u8]) -> Result<(), ()> {
+            self.sectors[start_sector as usize].copy_from_slice(src);
+            self.writes[self.write_count] = start_sector;
+            self.write_count += 1;
+            Ok(())
+        }
+
+        fn sector_count(&mut self) -> Result<u32, ()> {
+            Ok(TEST_SECTORS as u32)
+        }
+    }
+
+    fn first_byte<const N: usize>(cache: &SectorCache<N>, device: &mut TestDevice, sector: u32) -> u8 {
+        match cache.read_sector_as::<_, [u8; BLOCK_SIZE]>(device, sector) {
+            Ok(block) => block.get()[0],
+            Err(_) => panic!("couldn't read sector {}", sector),
+        }
+    }
+
+    fn modify<const N: usize>(cache: &SectorCache<N>, device: &mut TestDevice, sector: u32, value: u8) {
+        match cache.read_sector_as::<_, [u8; BLOCK_SIZE]>(device, sector) {
+            Ok(mut block) => block.get_mut()[0] = value,
+            Err(_) => panic!("couldn't read sector {}", sector),
+        }
+    }
+
+    #[test]
+    fn least_recently_used_slot_is_replaced() {
+        let mut device = TestDevice::new();
+        let cache = SectorCache::<3>::new();
+        for sector in 0..3 {
+            assert_eq!(first_byte(&cache, &mut device, sector), sector as u8);
+        }
+
+        // Sector 0 was loaded first but used since, so 1 is the oldest
+        assert_eq!(first_byte(&cache, &mut device, 0), 0);
+        assert_eq!(device.reads(), [0, 1, 2]);
+        assert_eq!(first_byte(&cache, &mut device, 3), 3);
+        assert_eq!(device.reads(), [0, 1, 2, 3]);
+
+        assert_eq!(first_byte(&cache, &mut device, 0), 0);
+        assert_eq!(first_byte(&cache, &mut device, 2), 2);
+        assert_eq!(first_byte(&cache, &mut device, 3), 3);
+        assert_eq!(device.reads(), [0, 1, 2, 3]);
+        assert_eq!(first_byte(&cache, &mut device, 1), 1);
+        assert_eq!(device.reads(), [0, 1, 2, 3, 1]);
+        assert!(device.writes().is_empty());
+    }
+
+    #[test]
+    fn only_the_evicted_slot_is_written_back() {
+        let mut device = TestDevice::new();
+        let cache = SectorCache::<3>::new();
+        modify(&cache, &mut device, 0, 0xa0);
+        modify(&cache, &mut device, 1, 0xa1);
+        assert_eq!(first_byte(&cache, &mut device, 2), 2);
+        assert!(device.writes().is_empty());
+
+        // Both 0 and 1 are dirty, but only 1 is the least recently used
+        assert_eq!(first_byte(&cache, &mut device, 0), 0xa0);
+        assert_eq!(first_byte(&cache, &mut device, 3), 3);
+        assert_eq!(device.writes(), [1]);
+        assert_eq!(device.sectors[1][0], 0xa1);
+        assert_eq!(device.sectors[0][0], 0);
+
+        // Sector 1 was written back before its slot was reused, so it reads back modified
+        assert_eq!(first_byte(&cache, &mut device, 1), 0xa1);
+        assert_eq!(device.reads(), [0, 1, 2, 3, 1]);
+
+        // Sector 0 is still the only dirty one
+        assert!(cache.sync(&mut device).is_ok());
+        assert_eq!(device.writes(), [1, 0]);
+        assert_eq!(device.sectors[0][0], 0xa0);
+        assert!(cache.sync(&mut device).is_ok());
+        assert_eq!(device.writes(), [1, 0]);
+    }
+
+    #[test]
+    fn locked_slots_are_not_replaced() {
+        let mut device = TestDevice::new();
+        let cache = SectorCache::<2>::new();
+        assert_eq!(first_byte(&cache, &mut device, 0), 0);
+        assert_eq!(first_byte(&cache, &mut device, 1), 1);
+
+        // Sector 0 is the oldest, but it's in use, so sector 1 has to make way
+        let block = match cache.read_sector_as::<_, [u8; BLOCK_SIZE]>(&mut device, 0) {
+            Ok(block) => block,
+            Err(_) => panic!("couldn't read sector 0"),
+        };
+        assert_eq!(first_byte(&cache, &mut device, 1), 1);
+        assert_eq!(first_byte(&cache, &mut device, 2), 2);
+        assert_eq!(block.get()[0], 0);
+        assert!(matches!(
+            cache.read_sector_as::<_, [u8; BLOCK_SIZE]>(&mut device, 0),
+            Err(FatError::DataBufferLocked)
+        ));
+        drop(block);
+        assert_eq!(device.reads(), [0, 1, 2]);
+    }
+}
@@ -1,19 +1,33 @@
 use crate::sdcard::BLOCK_SIZE;
 
-pub const DIR_SEPARATOR: u8 = '/' as u8;
+pub const DIR_SEPARATOR: u8 = b'/';
 pub const MAX_PATHNAME_LEN: usize = 256;
 pub const MAX_LFN_LEN: usize = 255;
 
-pub(crate) const SPACE: u8 = ' ' as u8;
-pub(crate) const DOT: u8 = '.' as u8;
+pub(crate) const SPACE: u8 = b' ';
+pub(crate) const DOT: u8 = b'.';
 
 pub(crate) const BYTES_PER_SECTOR: usize = BLOCK_SIZE;
 pub(crate) const LOG2_BYTES_PER_SECTOR: u8 = 9;
-pub(crate) const ROOT_CLUSTER: u32 = 2;
 pub(crate) const SECTOR_MASK: u16 = 0x1FF;
 
+// FAT32 volumes keep two identical copies of the FAT
+pub(crate) const FAT_COUNT: u8 = 2;
+
+// FAT32 entries are 4 bytes long, so each FAT sector holds 128 of them
+pub(crate) const FAT_ENTRIES_PER_SECTOR: usize = BYTES_PER_SECTOR / 4;
+pub(crate) const LOG2_FAT_ENTRIES_PER_SECTOR: u8 = LOG2_BYTES_PER_SECTOR - 2;
+
+// Only the low 28 bits of a FAT32 entry are used; the top 4 are reserved and must be ignored
+pub(crate) const FAT32_ENTRY_MASK: u32 = 0x0FFFFFFF;
+pub(crate) const FAT32_BAD_CLUSTER: u32 = 0x0FFFFFF7;
+pub(crate) const FAT32_END_OF_CHAIN: u32 = 0x0FFFFFF8;
+
 pub(crate) type SECTOR = [u8; BYTES_PER_SECTOR];
 
+// Both the MBR and a volume's boot sector end with these two bytes
+pub(crate) const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
 // File attributes
 pub(crate) const ATTR_CLOSED: u8 = 0;
 pub(crate) const ATTR_FILE: u8 = 0x08;
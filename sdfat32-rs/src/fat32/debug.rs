@@ -1,12 +1,22 @@
 use super::{
-    mbr::PartitionInfo,
+    gpt::{
+        GptPartitionInfo,
+        Guid,
+    },
+    mbr::{
+        PartitionInfo,
+        PartitionType,
+    },
     partition::Partition,
+    table::PartitionEntry,
 };
-use crate::hexfmt::hexfmt32_le;
-use avr_progmem_str::{
-    pm_write,
-    progmem_str,
+use crate::hexfmt::{
+    hexfmt32_le,
+    hexfmt_bytes,
 };
+use avr_progmem_str::pm_write;
+#[cfg(target_arch = "avr")]
+use avr_progmem_str::progmem_str;
 use ufmt::{
     uDebug,
     uWrite,
@@ -14,19 +24,24 @@ use ufmt::{
 };
 
 
-pub fn ptype_write<W>(serial: &mut Formatter<W>, ptype: u8) -> Result<(), W::Error>
+pub fn ptype_write<W>(serial: &mut Formatter<W>, ptype: PartitionType) -> Result<(), W::Error>
 where
     W: uWrite + ?Sized,
 {
     match ptype {
-        0x00 => pm_write!(serial, "Empty")?,
-        0x07 => pm_write!(serial, "NTFS")?,
-        0x0b => pm_write!(serial, "FAT32 + CHS")?,
-        0x0c => pm_write!(serial, "FAT32 + LBA")?,
-        0x0f => pm_write!(serial, "Extended Partition + LBA")?,
-        0x82 => pm_write!(serial, "Linux Swap Space")?,
-        0x83 => pm_write!(serial, "Linux File System")?,
-        _ => pm_write!(serial, "Unknown ({})", ptype)?,
+        PartitionType::Empty => pm_write!(serial, "Empty")?,
+        PartitionType::Fat12 => pm_write!(serial, "FAT12")?,
+        PartitionType::Fat16 => pm_write!(serial, "FAT16 + CHS")?,
+        PartitionType::Fat16Lba => pm_write!(serial, "FAT16 + LBA")?,
+        PartitionType::Ntfs => pm_write!(serial, "NTFS")?,
+        PartitionType::ExtendedChs => pm_write!(serial, "Extended Partition + CHS")?,
+        PartitionType::Fat32Chs => pm_write!(serial, "FAT32 + CHS")?,
+        PartitionType::Fat32Lba => pm_write!(serial, "FAT32 + LBA")?,
+        PartitionType::ExtendedLba => pm_write!(serial, "Extended Partition + LBA")?,
+        PartitionType::LinuxSwap => pm_write!(serial, "Linux Swap Space")?,
+        PartitionType::Linux => pm_write!(serial, "Linux File System")?,
+        PartitionType::GptProtective => pm_write!(serial, "GPT Protective")?,
+        PartitionType::Other(ptype) => pm_write!(serial, "Unknown ({})", ptype)?,
     };
     Ok(())
 }
@@ -37,7 +52,7 @@ impl uDebug for PartitionInfo {
         W: uWrite + ?Sized,
     {
         pm_write!(out, "  is_boot = {}; partition_type = ", self.boot)?;
-        ptype_write(out, self.ptype)?;
+        ptype_write(out, self.partition_type())?;
         pm_write!(
             out,
             "; begin_chs = {}/{}/{}; end_chs = {}/{}/{}; start_sector = "
@@ -56,6 +71,66 @@ impl uDebug for PartitionInfo {
     }
 }
 
+impl uDebug for Guid {
+    fn fmt<W>(&self, out: &mut Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        // The first three groups are stored little-endian
+        let g = &self.0;
+        hexfmt_bytes(out, &[g[3], g[2], g[1], g[0]])?;
+        out.write_char('-')?;
+        hexfmt_bytes(out, &[g[5], g[4]])?;
+        out.write_char('-')?;
+        hexfmt_bytes(out, &[g[7], g[6]])?;
+        out.write_char('-')?;
+        hexfmt_bytes(out, &g[8..10])?;
+        out.write_char('-')?;
+        hexfmt_bytes(out, &g[10..16])?;
+        Ok(())
+    }
+}
+
+impl uDebug for GptPartitionInfo {
+    fn fmt<W>(&self, out: &mut Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        pm_write!(out, "  type = ")?;
+        self.type_guid.fmt(out)?;
+        pm_write!(out, "; guid = ")?;
+        self.unique_guid.fmt(out)?;
+        pm_write!(out, "; first_sector = ")?;
+        hexfmt32_le(out, self.first_sector)?;
+        pm_write!(out, ", last_sector = ")?;
+        hexfmt32_le(out, self.last_sector)?;
+        pm_write!(out, "; name = ")?;
+        // Only ASCII names are printed as they are
+        for c in self.name.iter().take_while(|c| **c != 0) {
+            out.write_char(if *c < 0x80 { *c as u8 as char } else { '?' })?;
+        }
+        out.write_char('\n')?;
+        Ok(())
+    }
+}
+
+impl uDebug for PartitionEntry {
+    fn fmt<W>(&self, out: &mut Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        match self {
+            PartitionEntry::Mbr(info) => info.fmt(out),
+            PartitionEntry::Gpt(info) => info.fmt(out),
+            PartitionEntry::Superfloppy(total_sectors) => {
+                pm_write!(out, "  whole device, no partition table; length = ")?;
+                hexfmt32_le(out, *total_sectors)?;
+                out.write_char('\n')
+            },
+        }
+    }
+}
+
 impl uDebug for Partition {
     fn fmt<W>(&self, out: &mut Formatter<W>) -> Result<(), W::Error>
     where
@@ -71,9 +146,18 @@ impl uDebug for Partition {
         pm_write!(out, "  data start sector:   ")?;
         hexfmt32_le(out, self.data_start_sector)?;
         out.write_char('\n')?;
+        pm_write!(out, "  free cluster count:  ")?;
+        match self.free_cluster_count {
+            Some(free) => hexfmt32_le(out, free)?,
+            None => pm_write!(out, "unknown")?,
+        }
+        out.write_char('\n')?;
         pm_write!(out, "  fat start sector:    ")?;
         hexfmt32_le(out, self.fat_start_sector)?;
         out.write_char('\n')?;
+        pm_write!(out, "  root cluster:        ")?;
+        hexfmt32_le(out, self.root_cluster)?;
+        out.write_char('\n')?;
         pm_write!(out, "  sectors per cluster: {}\n", self.sectors_per_cluster)?;
         pm_write!(out, "  sectors per fat:     ")?;
         hexfmt32_le(out, self.sectors_per_fat)?;
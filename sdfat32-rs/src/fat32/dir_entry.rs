@@ -7,7 +7,9 @@ const DIRENT_ATTR_HIDDEN: u8 = 0x02;
 const DIRENT_ATTR_SYSTEM: u8 = 0x04;
 const DIRENT_ATTR_VOLUME_LABEL: u8 = 0x08;
 const DIRENT_ATTR_SUBDIR: u8 = 0x10;
+#[allow(dead_code)]
 const DIRENT_ATTR_ARCHIVE: u8 = 0x20;
+#[allow(dead_code)]
 const DIRENT_ATTR_DEVICE: u8 = 0x40;
 const DIRENT_ATTR_LONG_NAME: u8 = 0x0f;
 
@@ -42,6 +44,10 @@ impl DirEntry {
     }
 }
 
+// Directory entries are read straight out of sector buffers, so the layout has to match the disk
+// exactly: repr(C) keeps the fields in order and packed leaves out the padding that targets with
+// aligned u16/u32 (i.e., anything but AVR) would otherwise add
+#[repr(C, packed)]
 pub struct LFN {
     sequence_byte: u8,
     unicode1: [u8; 10],
@@ -82,6 +88,7 @@ impl LFN {
     }
 }
 
+#[repr(C, packed)]
 #[derive(Clone, Copy)]
 pub struct SFN {
     name: [u8; 11],
@@ -103,6 +110,24 @@ const PARENT_DIR: [u8; 11] = [DOT, DOT, SPACE, SPACE, SPACE, SPACE, SPACE, SPACE
 const DELETED: u8 = 0xe5;
 
 impl SFN {
+    // The entry at the start of the root directory that holds the volume label
+    pub(crate) fn volume_label(label: &[u8; 11]) -> SFN {
+        SFN {
+            name: *label,
+            attributes: DIRENT_ATTR_VOLUME_LABEL,
+            case_flags: 0,
+            creation_time_ms: 0,
+            creation_time: 0,
+            creation_date: 0,
+            access_date: 0,
+            first_cluster_high: 0,
+            modify_time: 0,
+            modify_date: 0,
+            first_cluster_low: 0,
+            size: 0,
+        }
+    }
+
     #[inline(always)]
     pub fn file_attributes(&self) -> u8 {
         // Attributes to pass on from the directory entry to the file object
@@ -36,11 +36,15 @@ impl File {
     }
 
     pub(crate) fn open(vol_id: u8, entry: &SFN, flags: u8) -> File {
-        Self::open_helper(vol_id, entry.first_cluster(), entry.file_attributes(), flags, entry.size())
+        // Regular files don't have an attribute bit of their own on disk, and a file with none of
+        // the attributes we keep (e.g., only the archive bit) would otherwise look closed
+        let attributes =
+            if entry.is_directory() { entry.file_attributes() } else { entry.file_attributes() | ATTR_FILE };
+        Self::open_helper(vol_id, entry.first_cluster(), attributes, flags, entry.size())
     }
 
-    pub(crate) fn open_root(vol_id: u8, flags: u8) -> File {
-        Self::open_helper(vol_id, ROOT_CLUSTER, ATTR_ROOT, flags, 0)
+    pub(crate) fn open_root(vol_id: u8, root_cluster: u32, flags: u8) -> File {
+        Self::open_helper(vol_id, root_cluster, ATTR_ROOT, flags, 0)
     }
 
     fn open_helper(vol_id: u8, start_cluster: u32, attributes: u8, flags: u8, size: u32) -> File {
@@ -86,6 +90,11 @@ impl File {
         self.flags & FLAG_READ > 0
     }
 
+    #[inline(always)]
+    pub fn is_writable(&self) -> bool {
+        self.flags & FLAG_WRITE > 0
+    }
+
     #[inline(always)]
     pub fn is_root(&self) -> bool {
         self.attributes & ATTR_ROOT > 0
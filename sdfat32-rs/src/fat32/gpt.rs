@@ -0,0 +1,262 @@
+use super::{
+    cache::SectorCache,
+    constants::*,
+    FatError,
+};
+use crate::block_device::{
+    BlockDevice,
+    BlockDeviceRef,
+};
+use core::cmp::min;
+
+
+const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+const GPT_HEADER_MIN_SIZE: u32 = 92;
+const GPT_ENTRY_MIN_SIZE: u32 = 128;
+const GPT_NAME_LEN: usize = 36;
+
+// The spec asks for room for at least 16 KiB of entries and nobody uses much more; the whole
+// array is read to check its CRC, so a header that asks for more than this is refused rather than
+// holding up the mount
+const GPT_ENTRY_ARRAY_MAX_SIZE: u32 = 256 * 1024;
+
+#[repr(C, packed)]
+struct GptHeader {
+    signature: [u8; 8],
+    _revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    _reserved: u32,
+    my_lba: u64,
+    _alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: [u8; 16],
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc32: u32,
+    _padding: [u8; 420],
+}
+
+#[repr(C, packed)]
+struct GptEntry {
+    type_guid: [u8; 16],
+    unique_guid: [u8; 16],
+    first_lba: u64,
+    last_lba: u64,
+    attributes: u64,
+    name: [u16; GPT_NAME_LEN],
+}
+
+// GUIDs are stored as they are on disk; the first three groups are little-endian
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    #[inline(always)]
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct GptPartitionInfo {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    // Inclusive, like the GPT itself
+    pub first_sector: u32,
+    pub last_sector: u32,
+    pub attributes: u64,
+    // UTF-16, padded with zeroes
+    pub name: [u16; GPT_NAME_LEN],
+}
+
+impl GptPartitionInfo {
+    #[inline(always)]
+    pub fn total_sectors(&self) -> u32 {
+        self.last_sector - self.first_sector + 1
+    }
+}
+
+// A GUID partition table; only the header is kept in memory, and entries are read from the
+// device when they're asked for
+pub struct Gpt {
+    pub disk_guid: Guid,
+    pub first_usable_sector: u32,
+    pub last_usable_sector: u32,
+    pub entry_count: u32,
+    // Set if the primary header or entry array was damaged and the backup copy is being used
+    pub is_backup: bool,
+    entries_sector: u32,
+    entry_size: u32,
+}
+
+impl Gpt {
+    pub fn read<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+    ) -> Result<Gpt, FatError> {
+        let sector_count = match device.borrow_mut().sector_count() {
+            Ok(sector_count) => sector_count,
+            Err(_) => return Err(FatError::BlockDeviceFailed),
+        };
+        Self::read_headers(device, cache, sector_count)
+    }
+
+    // Same as read, for callers that already know the size of the device
+    pub(crate) fn read_headers<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        sector_count: u32,
+    ) -> Result<Gpt, FatError> {
+        match Self::read_header(device, cache, 1) {
+            Ok(gpt) => Ok(gpt),
+            Err(FatError::BlockDeviceFailed) => Err(FatError::BlockDeviceFailed),
+            Err(_) => {
+                // The backup header is always in the last sector of the device, with its own copy
+                // of the entry array just before it
+                let mut gpt = Self::read_header(device, cache, sector_count.saturating_sub(1))?;
+                gpt.is_backup = true;
+                Ok(gpt)
+            },
+        }
+    }
+
+    // returns: the partition in slot `index`, or None if the slot is unused
+    pub fn read_entry<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        index: u32,
+    ) -> Result<Option<GptPartitionInfo>, FatError> {
+        if index >= self.entry_count {
+            return Err(FatError::BadPartitionNumber);
+        }
+
+        // Entry sizes are a power of two no bigger than a sector, so entries never straddle two
+        let offset = index * self.entry_size;
+        let sector = self.entries_sector + (offset >> LOG2_BYTES_PER_SECTOR);
+        let block = cache.read_sector_as::<_, SECTOR>(&mut *device.borrow_mut(), sector)?;
+        let pos = (offset & (SECTOR_MASK as u32)) as usize;
+        let entry = unsafe { &*(block.get()[pos..].as_ptr() as *const GptEntry) };
+
+        let type_guid = Guid(entry.type_guid);
+        if type_guid.is_nil() {
+            return Ok(None);
+        }
+        let (first_lba, last_lba) = (entry.first_lba, entry.last_lba);
+        if first_lba < self.first_usable_sector as u64
+            || last_lba > self.last_usable_sector as u64
+            || first_lba > last_lba
+        {
+            return Err(FatError::CorruptGPT);
+        }
+
+        Ok(Some(GptPartitionInfo {
+            type_guid,
+            unique_guid: Guid(entry.unique_guid),
+            first_sector: first_lba as u32,
+            last_sector: last_lba as u32,
+            attributes: entry.attributes,
+            name: entry.name,
+        }))
+    }
+
+    fn read_header<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        header_sector: u32,
+    ) -> Result<Gpt, FatError> {
+        let (gpt, entries_crc32) = {
+            let block = cache.read_sector_as::<_, GptHeader>(&mut *device.borrow_mut(), header_sector)?;
+            let header = block.get();
+            let (header_size, header_crc32) = (header.header_size, header.header_crc32);
+            if header.signature != GPT_SIGNATURE
+                || header_size < GPT_HEADER_MIN_SIZE
+                || header_size > BYTES_PER_SECTOR as u32
+            {
+                return Err(FatError::CorruptGPT);
+            }
+
+            // The header CRC covers the header with its own CRC field zeroed
+            let raw = unsafe { &*(header as *const GptHeader as *const SECTOR) };
+            let mut crc = Crc32::new();
+            crc.update(&raw[..16]);
+            crc.update(&[0; 4]);
+            crc.update(&raw[20..header_size as usize]);
+            if crc.finish() != header_crc32 {
+                return Err(FatError::CorruptGPT);
+            }
+
+            // Sectors are 32-bit everywhere else, so a table that reaches past that can't be used
+            let (my_lba, entries_lba) = (header.my_lba, header.entries_lba);
+            let (first_usable_lba, last_usable_lba) = (header.first_usable_lba, header.last_usable_lba);
+            let (entry_count, entry_size) = (header.entry_count, header.entry_size);
+            if my_lba != header_sector as u64
+                || last_usable_lba > u32::MAX as u64
+                || entries_lba > u32::MAX as u64
+                || first_usable_lba > last_usable_lba
+                || entry_size < GPT_ENTRY_MIN_SIZE
+                || entry_size > BYTES_PER_SECTOR as u32
+                || !entry_size.is_power_of_two()
+                || !matches!(entry_count.checked_mul(entry_size), Some(size) if size <= GPT_ENTRY_ARRAY_MAX_SIZE)
+            {
+                return Err(FatError::CorruptGPT);
+            }
+
+            let gpt = Gpt {
+                disk_guid: Guid(header.disk_guid),
+                first_usable_sector: first_usable_lba as u32,
+                last_usable_sector: last_usable_lba as u32,
+                entry_count,
+                is_backup: false,
+                entries_sector: entries_lba as u32,
+                entry_size,
+            };
+            (gpt, header.entries_crc32)
+        };
+
+        // The entry array has its own CRC, which means reading the whole thing once up front
+        let mut crc = Crc32::new();
+        let mut remaining = gpt.entry_count * gpt.entry_size;
+        let mut sector = gpt.entries_sector;
+        while remaining > 0 {
+            let block = cache.read_sector_as::<_, SECTOR>(&mut *device.borrow_mut(), sector)?;
+            let n = min(remaining, BYTES_PER_SECTOR as u32);
+            crc.update(&block.get()[..n as usize]);
+            remaining -= n;
+            sector += 1;
+        }
+        if crc.finish() != entries_crc32 {
+            return Err(FatError::CorruptGPT);
+        }
+        Ok(gpt)
+    }
+}
+
+// Standard CRC-32 (as used by zip, Ethernet, etc.), a nibble at a time so the table stays small
+const CRC32_TABLE: [u32; 16] = [
+    0x00000000, 0x1DB71064, 0x3B6E20C8, 0x26D930AC, 0x76DC4190, 0x6B6B51F4, 0x4DB26158, 0x5005713C, 0xEDB88320,
+    0xF00F9344, 0xD6D6A3E8, 0xCB61B38C, 0x9B64C2B0, 0x86D3D2D4, 0xA00AE278, 0xBDBDF21C,
+];
+
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) fn new() -> Crc32 {
+        Crc32(0xFFFFFFFF)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.0 ^= *b as u32;
+            self.0 = (self.0 >> 4) ^ CRC32_TABLE[(self.0 & 0x0f) as usize];
+            self.0 = (self.0 >> 4) ^ CRC32_TABLE[(self.0 & 0x0f) as usize];
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u32 {
+        !self.0
+    }
+}
@@ -1,12 +1,108 @@
-use super::FatError;
-use crate::sdcard::{
-    SdCard,
-    FS_BUFFER,
+use super::{
+    cache::SectorCache,
+    constants::*,
+    partition::Partition,
+    FatError,
 };
-use core::cell::RefCell;
+use crate::block_device::{
+    BlockDevice,
+    BlockDeviceRef,
+};
+
+
+// Upper bound on how many extended boot records get followed, in case the chain is corrupt
+const MAX_LOGICAL_PARTITIONS: u32 = 64;
+
+// Nothing uses CHS addresses any more, but they still get filled in for old tools, using the
+// usual made-up geometry; anything past the last cylinder gets the largest address there is
+pub(crate) const CHS_HEADS: u32 = 255;
+pub(crate) const CHS_SECTORS_PER_TRACK: u32 = 63;
+const CHS_MAX_CYLINDER: u32 = 1023;
+const CHS_MAX: [u8; 3] = [0xfe, 0xff, 0xff];
+
+
+// The partition types that mean something to this library, or are common enough on SD cards to
+// be worth naming; everything else is kept as the raw byte
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Empty,
+    Fat12,
+    Fat16,
+    Fat16Lba,
+    Fat32Chs,
+    Fat32Lba,
+    ExtendedChs,
+    ExtendedLba,
+    Ntfs,
+    LinuxSwap,
+    Linux,
+    GptProtective,
+    Other(u8),
+}
+
+impl PartitionType {
+    #[inline(always)]
+    pub fn is_fat32(&self) -> bool {
+        *self == PartitionType::Fat32Chs || *self == PartitionType::Fat32Lba
+    }
+
+    // Any of the FAT types; cards are sometimes formatted FAT32 but left with a FAT16 type in
+    // the MBR, so those are worth trying too
+    #[inline(always)]
+    pub fn is_fat(&self) -> bool {
+        match self {
+            PartitionType::Fat12 | PartitionType::Fat16 | PartitionType::Fat16Lba => true,
+            _ => self.is_fat32(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_extended(&self) -> bool {
+        *self == PartitionType::ExtendedChs || *self == PartitionType::ExtendedLba
+    }
+}
+
+impl From<PartitionType> for u8 {
+    fn from(partition_type: PartitionType) -> u8 {
+        match partition_type {
+            PartitionType::Empty => 0x00,
+            PartitionType::Fat12 => 0x01,
+            PartitionType::Fat16 => 0x06,
+            PartitionType::ExtendedChs => 0x05,
+            PartitionType::Ntfs => 0x07,
+            PartitionType::Fat32Chs => 0x0b,
+            PartitionType::Fat32Lba => 0x0c,
+            PartitionType::Fat16Lba => 0x0e,
+            PartitionType::ExtendedLba => 0x0f,
+            PartitionType::LinuxSwap => 0x82,
+            PartitionType::Linux => 0x83,
+            PartitionType::GptProtective => 0xee,
+            PartitionType::Other(ptype) => ptype,
+        }
+    }
+}
 
+impl From<u8> for PartitionType {
+    fn from(ptype: u8) -> PartitionType {
+        match ptype {
+            0x00 => PartitionType::Empty,
+            0x01 => PartitionType::Fat12,
+            0x04 | 0x06 => PartitionType::Fat16,
+            0x05 => PartitionType::ExtendedChs,
+            0x07 => PartitionType::Ntfs,
+            0x0b => PartitionType::Fat32Chs,
+            0x0c => PartitionType::Fat32Lba,
+            0x0e => PartitionType::Fat16Lba,
+            0x0f => PartitionType::ExtendedLba,
+            0x82 => PartitionType::LinuxSwap,
+            0x83 => PartitionType::Linux,
+            0xee => PartitionType::GptProtective,
+            _ => PartitionType::Other(ptype),
+        }
+    }
+}
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Clone, Copy)]
 pub struct PartitionInfo {
     pub boot: u8,
@@ -30,9 +126,59 @@ impl PartitionInfo {
             total_sectors: 0,
         }
     }
+
+    // An entry for a new partition table, with the CHS fields worked out from the LBA ones
+    pub fn with_lba(partition_type: PartitionType, start_sector: u32, total_sectors: u32) -> PartitionInfo {
+        let mut info = PartitionInfo::new();
+        info.ptype = partition_type.into();
+        info.start_sector = start_sector;
+        info.total_sectors = total_sectors;
+        info.set_chs();
+        info
+    }
+
+    #[inline(always)]
+    pub fn partition_type(&self) -> PartitionType {
+        PartitionType::from(self.ptype)
+    }
+
+    #[inline(always)]
+    pub fn is_extended(&self) -> bool {
+        self.partition_type().is_extended()
+    }
+
+    // Only the top bit means anything; the rest have to be clear
+    #[inline(always)]
+    fn has_valid_boot_flag(&self) -> bool {
+        self.boot & 0x7f == 0
+    }
+
+    #[inline(always)]
+    fn end_sector(&self) -> u64 {
+        self.start_sector as u64 + self.total_sectors as u64
+    }
+
+    fn set_chs(&mut self) {
+        if self.total_sectors == 0 {
+            return;
+        }
+        self.begin_chs = lba_to_chs(self.start_sector);
+        self.end_chs = lba_to_chs((self.end_sector() - 1) as u32);
+    }
 }
 
-#[repr(packed)]
+// Sectors count from 1 and the top two bits of the cylinder go in the top of the sector byte
+fn lba_to_chs(lba: u32) -> [u8; 3] {
+    let cylinder = lba / (CHS_HEADS * CHS_SECTORS_PER_TRACK);
+    if cylinder > CHS_MAX_CYLINDER {
+        return CHS_MAX;
+    }
+    let head = (lba / CHS_SECTORS_PER_TRACK) % CHS_HEADS;
+    let sector = lba % CHS_SECTORS_PER_TRACK + 1;
+    [head as u8, (sector as u8) | ((cylinder >> 2) as u8 & 0xc0), cylinder as u8]
+}
+
+#[repr(C, packed)]
 pub struct Mbr {
     pub boot_code: [u8; 446],
     pub partitions: [PartitionInfo; 4],
@@ -40,11 +186,215 @@ pub struct Mbr {
 }
 
 impl Mbr {
-    pub fn read_part_info<CSPIN: avr_hal_generic::port::PinOps>(
-        sdcard: &RefCell<SdCard<CSPIN>>,
+    pub fn read_part_info<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
     ) -> Result<[PartitionInfo; 4], FatError> {
-        let mut sd_borrow_mut = sdcard.borrow_mut();
-        let mbr = sd_borrow_mut.read_sector_as::<Mbr>(FS_BUFFER, 0)?;
-        Ok(mbr.get().partitions)
+        let sector_count = match device.borrow_mut().sector_count() {
+            Ok(sector_count) => sector_count,
+            Err(_) => return Err(FatError::BlockDeviceFailed),
+        };
+        Self::read(device, cache, sector_count)
+    }
+
+    // Same as read_part_info, for callers that already know the size of the device
+    pub(crate) fn read<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        sector_count: u32,
+    ) -> Result<[PartitionInfo; 4], FatError> {
+        let mut partitions = {
+            let mbr_block = cache.read_sector_as::<_, Mbr>(&mut *device.borrow_mut(), 0)?;
+            let mbr = mbr_block.get();
+            if mbr.signature != BOOT_SIGNATURE {
+                return Err(FatError::CorruptMBR);
+            }
+            mbr.partitions
+        };
+        Self::check_partitions(&mut partitions, sector_count)?;
+        Ok(partitions)
+    }
+
+    // Write a new partition table with up to four primary partitions, which have their CHS fields
+    // filled in; any boot code and disk signature already in the MBR are kept.  The entries are
+    // checked the same way as when they're read, so the table can always be read back.
+    // returns: the entries as they were written, the same as read_part_info would return them
+    pub fn write_part_info<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        entries: &[PartitionInfo],
+    ) -> Result<[PartitionInfo; 4], FatError> {
+        if entries.len() > 4 {
+            return Err(FatError::BadPartitionNumber);
+        }
+        let mut partitions = [PartitionInfo::new(); 4];
+        partitions[..entries.len()].copy_from_slice(entries);
+        if partitions.iter().any(|info| info.ptype != 0 && !info.has_valid_boot_flag()) {
+            return Err(FatError::CorruptMBR);
+        }
+        let sector_count = match device.borrow_mut().sector_count() {
+            Ok(sector_count) => sector_count,
+            Err(_) => return Err(FatError::BlockDeviceFailed),
+        };
+        Self::check_partitions(&mut partitions, sector_count)?;
+        for info in partitions.iter_mut().filter(|info| info.ptype != 0) {
+            info.set_chs();
+        }
+
+        // A volume's boot sector at sector 0 isn't boot code that's worth keeping, and would
+        // make the device look partitionless if it was left there
+        let keep_boot_code = Partition::probe(device, cache, 0)?.is_none();
+        {
+            let mut mbr_block = cache.read_sector_as::<_, Mbr>(&mut *device.borrow_mut(), 0)?;
+            let mbr = mbr_block.get_mut();
+            if !keep_boot_code || mbr.signature != BOOT_SIGNATURE {
+                mbr.boot_code = [0; 446];
+            }
+            mbr.partitions = partitions;
+            mbr.signature = BOOT_SIGNATURE;
+        }
+        cache.sync_sector(&mut *device.borrow_mut(), 0)?;
+        Ok(partitions)
+    }
+
+    // Write a partition table with a single FAT32 partition filling the device.  Like the SD
+    // Association's formatter, the partition starts one erase block in, so the MBR has a block to
+    // itself and the volume lines up with the card's allocation units.
+    pub fn write_fat32_part_info<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+    ) -> Result<[PartitionInfo; 4], FatError> {
+        let (sector_count, erase_block_sectors) = {
+            let mut d = device.borrow_mut();
+            match (d.sector_count(), d.erase_block_sectors()) {
+                (Ok(sector_count), Ok(erase_block_sectors)) => (sector_count, erase_block_sectors.max(1)),
+                _ => return Err(FatError::BlockDeviceFailed),
+            }
+        };
+        if sector_count <= erase_block_sectors {
+            return Err(FatError::VolumeFull);
+        }
+        let info =
+            PartitionInfo::with_lba(PartitionType::Fat32Lba, erase_block_sectors, sector_count - erase_block_sectors);
+        Self::write_part_info(device, cache, &[info])
+    }
+
+    // Entries have to fit on the device and mustn't overlap each other or the MBR itself.  The
+    // protective entry in front of a GPT is left alone, since it's allowed to claim more of the
+    // device than there is.  An entry with a garbled boot flag is taken to be junk and emptied,
+    // rather than refusing the whole table over it.
+    fn check_partitions(partitions: &mut [PartitionInfo; 4], sector_count: u32) -> Result<(), FatError> {
+        for info in partitions.iter_mut().filter(|info| info.ptype != 0 && !info.has_valid_boot_flag()) {
+            *info = PartitionInfo::new();
+        }
+        let is_checked =
+            |info: &PartitionInfo| info.ptype != 0 && info.partition_type() != PartitionType::GptProtective;
+        for (i, info) in partitions.iter().enumerate() {
+            if !is_checked(info) {
+                continue;
+            }
+            if info.start_sector == 0 || info.total_sectors == 0 || info.end_sector() > sector_count as u64 {
+                return Err(FatError::CorruptMBR);
+            }
+            let overlaps = partitions[i + 1..].iter().any(|other| {
+                is_checked(other)
+                    && (info.start_sector as u64) < other.end_sector()
+                    && (other.start_sector as u64) < info.end_sector()
+            });
+            if overlaps {
+                return Err(FatError::CorruptMBR);
+            }
+        }
+        Ok(())
+    }
+
+    // returns: the number of logical partitions in the extended partition `extended`; if the
+    // chain is broken partway, only the ones before the break are counted so the rest of the
+    // table stays usable
+    pub(crate) fn count_logical<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        extended: &PartitionInfo,
+    ) -> Result<u32, FatError> {
+        let mut count = 0;
+        match Self::walk_logical(device, cache, extended, |_, _| {
+            count += 1;
+            true
+        }) {
+            Ok(()) | Err(FatError::CorruptMBR) => Ok(count),
+            Err(e) => Err(e),
+        }
+    }
+
+    // returns: logical partition `index` (counting from 0) in the extended partition `extended`,
+    // or None if there aren't that many
+    pub(crate) fn read_logical<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        extended: &PartitionInfo,
+        index: u32,
+    ) -> Result<Option<PartitionInfo>, FatError> {
+        let mut found = None;
+        Self::walk_logical(device, cache, extended, |i, logical| {
+            if i == index {
+                found = Some(*logical);
+            }
+            i < index
+        })?;
+        Ok(found)
+    }
+
+    // Each extended boot record is laid out like an MBR, with the logical partition in the first
+    // slot (relative to the EBR itself) and a link to the next EBR in the second (relative to the
+    // start of the extended partition).  Each logical partition gets handed to `func` with its
+    // start sector made absolute, until `func` returns false or the chain ends.  Logical
+    // partitions have to fit in the extended partition, and each EBR has to come after the one
+    // before it and its partition, so they can't overlap and the chain can't loop.
+    fn walk_logical<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        extended: &PartitionInfo,
+        mut func: impl FnMut(u32, &PartitionInfo) -> bool,
+    ) -> Result<(), FatError> {
+        let (extended_start, extended_size) = (extended.start_sector, extended.total_sectors);
+        let extended_end = extended_start as u64 + extended_size as u64;
+        let mut ebr_sector = extended_start as u64;
+        let mut index = 0;
+        for _ in 0..MAX_LOGICAL_PARTITIONS {
+            let (mut logical, next) = {
+                let ebr_block = cache.read_sector_as::<_, Mbr>(&mut *device.borrow_mut(), ebr_sector as u32)?;
+                let ebr = ebr_block.get();
+                if ebr.signature != BOOT_SIGNATURE {
+                    return Err(FatError::CorruptMBR);
+                }
+                (ebr.partitions[0], ebr.partitions[1])
+            };
+
+            // The first EBR's slot can be empty if there are no logical partitions left in it
+            let mut used_end = ebr_sector + 1;
+            if logical.ptype != 0 {
+                let (logical_start, logical_size) = (logical.start_sector, logical.total_sectors);
+                let start = ebr_sector + logical_start as u64;
+                used_end = start + logical_size as u64;
+                if logical_start == 0 || logical_size == 0 || used_end > extended_end || start > u32::MAX as u64 {
+                    return Err(FatError::CorruptMBR);
+                }
+                logical.start_sector = start as u32;
+                if !func(index, &logical) {
+                    return Ok(());
+                }
+                index += 1;
+            }
+
+            if !next.is_extended() {
+                return Ok(());
+            }
+            let next_sector = extended_start as u64 + next.start_sector as u64;
+            if next_sector < used_end || next_sector >= extended_end {
+                return Err(FatError::CorruptMBR);
+            }
+            ebr_sector = next_sector;
+        }
+        Err(FatError::CorruptMBR)
     }
 }
@@ -1,16 +1,33 @@
+mod cache;
 pub mod constants;
 mod debug;
 mod dir_entry;
 mod file;
+mod gpt;
 mod mbr;
 mod partition;
+mod table;
 mod volume;
 
 use crate::sdcard::SdCardError;
+pub use cache::SectorCache;
 pub use dir_entry::DirEntry;
 pub use file::File;
-pub use mbr::Mbr;
+pub use gpt::{
+    Gpt,
+    GptPartitionInfo,
+    Guid,
+};
+pub use mbr::{
+    Mbr,
+    PartitionInfo,
+    PartitionType,
+};
 pub use partition::Partition;
+pub use table::{
+    PartitionEntry,
+    PartitionTable,
+};
 pub use volume::Volume;
 
 pub enum FatError {
@@ -31,6 +48,11 @@ pub enum FatError {
     LfnParseError,
     ParsePathError,
     FileNotFound,
+    DataBufferLocked,
+    WriteError,
+    VolumeFull,
+    CorruptGPT,
+    NotFatPartition,
     Unknown,
 }
 
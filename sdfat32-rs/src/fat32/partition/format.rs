@@ -0,0 +1,254 @@
+use super::{
+    FatCache,
+    FatError,
+    FsInfo,
+    Partition,
+    PartitionBootSector,
+    PartitionEntry,
+    FSINFO_LEAD_SIGNATURE,
+    FSINFO_STRUCT_SIGNATURE,
+    FSINFO_TRAIL_SIGNATURE,
+};
+use crate::{
+    block_device::{
+        BlockDevice,
+        BlockDeviceRef,
+    },
+    fat32::{
+        cache::SectorCache,
+        constants::*,
+        dir_entry::SFN,
+        mbr::{
+            CHS_HEADS,
+            CHS_SECTORS_PER_TRACK,
+        },
+    },
+};
+use core::{
+    cell::Cell,
+    cmp::min,
+};
+
+
+// Where things go in the reserved area at the start of the volume
+const FORMAT_RESERVED_SECTORS: u32 = 32;
+const FORMAT_FS_INFO_SECTOR: u16 = 1;
+const FORMAT_BACKUP_BOOT_SECTOR: u16 = 6;
+
+// 32 KiB clusters, which is what the SD Association's formatter uses for FAT32
+const FORMAT_MAX_SECTORS_PER_CLUSTER: u8 = 64;
+
+// Anything with fewer clusters than this would be taken for FAT16
+const FORMAT_MIN_CLUSTERS: u32 = 65525;
+
+// Cards with huge allocation units still only get aligned to 16 MiB, which keeps the reserved
+// sector count in range
+const FORMAT_MAX_ALIGNMENT: u32 = 32768;
+
+const FORMAT_OEM_NAME: [u8; 8] = *b"MSWIN4.1";
+const FORMAT_VOLUME_TYPE: [u8; 8] = *b"FAT32   ";
+const FORMAT_NO_LABEL: [u8; 11] = *b"NO NAME    ";
+const MEDIA_FIXED: u8 = 0xf8;
+const DRIVE_NUMBER_FIXED: u8 = 0x80;
+const EXT_BOOT_SIGNATURE: u8 = 0x29;
+
+// Empty sectors are written this many at a time, which the SD card does as a single multi-block
+// write; AVRs don't have the RAM to spare for more than one
+#[cfg(target_arch = "avr")]
+const FORMAT_ZERO_BATCH_SECTORS: usize = 1;
+#[cfg(not(target_arch = "avr"))]
+const FORMAT_ZERO_BATCH_SECTORS: usize = 8;
+
+// Jumps over the BPB to an endless loop, since there's nothing to boot
+const FORMAT_JUMP_INSTR: [u8; 3] = [0xeb, 0x58, 0x90];
+const FORMAT_BOOT_CODE: [u8; 2] = [0xeb, 0xfe];
+
+impl Partition {
+    // Put a new, empty FAT32 volume on a partition.  The boot sector goes last, after it's been
+    // cleared out first, so a format that doesn't finish leaves nothing that looks mountable.
+    pub(crate) fn format<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        partition_entry: &PartitionEntry,
+        volume_label: &[u8; 11],
+        volume_serial: u32,
+    ) -> Result<(), FatError> {
+        if !partition_entry.is_fat() {
+            return Err(FatError::NotFatPartition);
+        }
+        let erase_block_sectors = match device.borrow_mut().erase_block_sectors() {
+            Ok(erase_block_sectors) => erase_block_sectors,
+            Err(_) => return Err(FatError::BlockDeviceFailed),
+        };
+        let start_sector = partition_entry.start_sector();
+        let total_sectors = partition_entry.total_sectors();
+        let partition = Self::layout(start_sector, total_sectors, erase_block_sectors, volume_label)?;
+
+        let mut sector: SECTOR = [0; BYTES_PER_SECTOR];
+        write_sectors(device, cache, start_sector, &sector)?;
+
+        // Both FATs are empty apart from the two reserved entries and the root directory's.  The
+        // FATs are next to each other, so they're cleared in one go (the second FAT's first
+        // sector included, even though it gets written again below).
+        let fat_sectors = FAT_COUNT as u32 * partition.sectors_per_fat;
+        zero_sectors(device, cache, partition.fat_start_sector + 1, fat_sectors - 1)?;
+        let root_start_sector = partition.cluster_start_sector(partition.root_cluster);
+        zero_sectors(device, cache, root_start_sector + 1, partition.sectors_per_cluster as u32 - 1)?;
+
+        // Entry 0 repeats the media type, and entry 1 has the "clean" and "no errors" bits set
+        let first_entries = [0x0FFFFF00 | MEDIA_FIXED as u32, 0x0FFFFFFF, FAT32_END_OF_CHAIN];
+        for (i, entry) in first_entries.iter().enumerate() {
+            sector[i * 4..i * 4 + 4].copy_from_slice(&entry.to_le_bytes());
+        }
+        for fat in 0..FAT_COUNT as u32 {
+            write_sectors(device, cache, partition.fat_start_sector + fat * partition.sectors_per_fat, &sector)?;
+        }
+
+        sector = [0; BYTES_PER_SECTOR];
+        if *volume_label != FORMAT_NO_LABEL {
+            unsafe { *(sector.as_mut_ptr() as *mut SFN) = SFN::volume_label(volume_label) };
+        }
+        write_sectors(device, cache, root_start_sector, &sector)?;
+
+        // FSInfo and the boot sector both have a backup copy
+        sector = [0; BYTES_PER_SECTOR];
+        partition.fill_fs_info(&mut sector);
+        write_sectors(device, cache, start_sector + FORMAT_FS_INFO_SECTOR as u32, &sector)?;
+        write_sectors(
+            device,
+            cache,
+            start_sector + (FORMAT_BACKUP_BOOT_SECTOR + FORMAT_FS_INFO_SECTOR) as u32,
+            &sector,
+        )?;
+
+        sector = [0; BYTES_PER_SECTOR];
+        partition.fill_boot_sector(&mut sector, start_sector, total_sectors, volume_serial);
+        write_sectors(device, cache, start_sector + FORMAT_BACKUP_BOOT_SECTOR as u32, &sector)?;
+        write_sectors(device, cache, start_sector, &sector)
+    }
+
+    // Work out the geometry of a new volume the way the SD Association's formatter does: 32 KiB
+    // clusters, with the FATs and the data area each starting on an erase block boundary.  Volumes
+    // too small for that many clusters to fit get smaller clusters, down to a single sector.
+    fn layout(
+        start_sector: u32,
+        total_sectors: u32,
+        erase_block_sectors: u32,
+        volume_label: &[u8; 11],
+    ) -> Result<Partition, FatError> {
+        let alignment = erase_block_sectors.clamp(1, FORMAT_MAX_ALIGNMENT) as u64;
+        let end_sector = start_sector as u64 + total_sectors as u64;
+        let fat_start_sector = align_up(start_sector as u64 + FORMAT_RESERVED_SECTORS as u64, alignment);
+
+        // Both FATs together have to fill whole erase blocks for the data area to line up
+        let fat_alignment = if alignment.is_multiple_of(2) { alignment / 2 } else { alignment };
+
+        let mut log2_sectors_per_cluster = FORMAT_MAX_SECTORS_PER_CLUSTER.trailing_zeros() as u8;
+        loop {
+            let sectors_per_cluster = 1u64 << log2_sectors_per_cluster;
+
+            // Each FAT needs an entry for every cluster as well as the two reserved ones, and the
+            // FATs themselves take space away from the clusters
+            let available = end_sector.saturating_sub(fat_start_sector);
+            let entries_per_fat_sector = FAT_ENTRIES_PER_SECTOR as u64 * sectors_per_cluster;
+            let sectors_per_fat =
+                (available + 2 * sectors_per_cluster).div_ceil(entries_per_fat_sector + FAT_COUNT as u64);
+            let sectors_per_fat = align_up(sectors_per_fat, fat_alignment);
+            let data_start_sector = fat_start_sector + FAT_COUNT as u64 * sectors_per_fat;
+            let data_cluster_count = end_sector.saturating_sub(data_start_sector) >> log2_sectors_per_cluster;
+
+            if data_cluster_count >= FORMAT_MIN_CLUSTERS as u64 {
+                return Ok(Partition {
+                    alloc_search_start: 2,
+                    cluster_sector_mask: (sectors_per_cluster - 1) as u8,
+                    data_cluster_count: data_cluster_count as u32,
+                    data_start_sector: data_start_sector as u32,
+                    fat_start_sector: fat_start_sector as u32,
+                    // Everything but the root directory
+                    free_cluster_count: Some(data_cluster_count as u32 - 1),
+                    log2_sectors_per_cluster,
+                    root_cluster: 2,
+                    sectors_per_cluster: sectors_per_cluster as u8,
+                    sectors_per_fat: sectors_per_fat as u32,
+                    volume_label: *volume_label,
+                    fat_cache: FatCache::new(),
+                    fs_info_sector: Some(start_sector + FORMAT_FS_INFO_SECTOR as u32),
+                    fs_info_dirty: Cell::new(false),
+                });
+            }
+            if log2_sectors_per_cluster == 0 {
+                return Err(FatError::UnsupportedVersion);
+            }
+            log2_sectors_per_cluster -= 1;
+        }
+    }
+
+    fn fill_boot_sector(&self, sector: &mut SECTOR, start_sector: u32, total_sectors: u32, volume_serial: u32) {
+        let pbs = unsafe { &mut *(sector.as_mut_ptr() as *mut PartitionBootSector) };
+        pbs.jump_instr = FORMAT_JUMP_INSTR;
+        pbs._oem_name = FORMAT_OEM_NAME;
+        pbs._boot_code[..FORMAT_BOOT_CODE.len()].copy_from_slice(&FORMAT_BOOT_CODE);
+        pbs.signature = BOOT_SIGNATURE;
+
+        let bp = &mut pbs.bios_params;
+        bp.bytes_per_sector = BYTES_PER_SECTOR as u16;
+        bp.sectors_per_cluster = self.sectors_per_cluster;
+        bp.reserved_sector_count = (self.fat_start_sector - start_sector) as u16;
+        bp.fat_count = FAT_COUNT;
+        bp._media_type = MEDIA_FIXED;
+        bp._sectors_per_track = CHS_SECTORS_PER_TRACK as u16;
+        bp._head_count = CHS_HEADS as u16;
+        bp._hidden_sectors = start_sector;
+        bp.total_sectors_32 = total_sectors;
+        bp.sectors_per_fat_32 = self.sectors_per_fat;
+        bp.fat_32_root_cluster = self.root_cluster;
+        bp.fat_32_fs_info_sector = FORMAT_FS_INFO_SECTOR;
+        bp._fat_32_back_boot_sector = FORMAT_BACKUP_BOOT_SECTOR;
+        bp._physical_drive_number = DRIVE_NUMBER_FIXED;
+        bp._ext_signature = EXT_BOOT_SIGNATURE;
+        bp._volume_serial_number = volume_serial;
+        bp.volume_label = self.volume_label;
+        bp._volume_type = FORMAT_VOLUME_TYPE;
+    }
+
+    fn fill_fs_info(&self, sector: &mut SECTOR) {
+        let fs_info = unsafe { &mut *(sector.as_mut_ptr() as *mut FsInfo) };
+        fs_info.lead_signature = FSINFO_LEAD_SIGNATURE;
+        fs_info.struct_signature = FSINFO_STRUCT_SIGNATURE;
+        fs_info.free_count = self.free_cluster_count.unwrap_or(0);
+        fs_info.next_free = self.alloc_search_start;
+        fs_info.trail_signature = FSINFO_TRAIL_SIGNATURE;
+    }
+}
+
+#[inline(always)]
+fn align_up(n: u64, alignment: u64) -> u64 {
+    n.div_ceil(alignment) * alignment
+}
+
+// Everything goes through the cache, so any copies of the old volume's sectors it has are dropped
+fn write_sectors<D: BlockDevice, const N: usize>(
+    device: BlockDeviceRef<D>,
+    cache: &SectorCache<N>,
+    start_sector: u32,
+    data: &[u8],
+) -> Result<(), FatError> {
+    cache.write_sectors(&mut *device.borrow_mut(), start_sector, data)
+}
+
+fn zero_sectors<D: BlockDevice, const N: usize>(
+    device: BlockDeviceRef<D>,
+    cache: &SectorCache<N>,
+    start_sector: u32,
+    sector_count: u32,
+) -> Result<(), FatError> {
+    let zeros = [0; FORMAT_ZERO_BATCH_SECTORS * BYTES_PER_SECTOR];
+    let end_sector = start_sector + sector_count;
+    let mut sector = start_sector;
+    while sector < end_sector {
+        let batch = min(end_sector - sector, FORMAT_ZERO_BATCH_SECTORS as u32);
+        write_sectors(device, cache, sector, &zeros[..batch as usize * BYTES_PER_SECTOR])?;
+        sector += batch;
+    }
+    Ok(())
+}
@@ -0,0 +1,670 @@
+mod format;
+
+use super::{
+    cache::SectorCache,
+    constants::*,
+    table::PartitionEntry,
+    FatError,
+};
+use crate::block_device::{
+    BlockDevice,
+    BlockDeviceRef,
+};
+use core::{
+    cell::{
+        Cell,
+        Ref,
+        RefCell,
+        RefMut,
+    },
+    cmp::min,
+};
+
+
+#[repr(C, packed)]
+struct BiosParameterBlock {
+    bytes_per_sector: u16,
+    sectors_per_cluster: u8,
+    reserved_sector_count: u16,
+    fat_count: u8,
+    _root_dir_entry_count: u16, // Unused in FAT32
+    _total_sectors_16: u16,     // Unused in FAT32
+    _media_type: u8,
+    _sectors_per_fat_16: u16, // Unused in FAT32
+    _sectors_per_track: u16,
+    _head_count: u16,
+    _hidden_sectors: u32,
+    total_sectors_32: u32,
+    sectors_per_fat_32: u32,
+    _fat_32_flags: u16,
+    _fat_32_version: u16,
+    fat_32_root_cluster: u32,
+    fat_32_fs_info_sector: u16,
+    _fat_32_back_boot_sector: u16,
+    _fat_32_reserved: [u8; 12],
+    _physical_drive_number: u8,
+    _ext_reserved: u8,
+    _ext_signature: u8,
+    _volume_serial_number: u32,
+    volume_label: [u8; 11],
+    _volume_type: [u8; 8],
+}
+
+#[repr(C, packed)]
+struct PartitionBootSector {
+    jump_instr: [u8; 3],
+    _oem_name: [u8; 8],
+    bios_params: BiosParameterBlock,
+    _boot_code: [u8; 420],
+    signature: [u8; 2],
+}
+
+impl PartitionBootSector {
+    // An MBR's boot code could start with a jump too, so the BPB has to look like a FAT32 one
+    // as well before this is taken to be a boot sector
+    fn is_fat32(&self) -> bool {
+        let bp = &self.bios_params;
+        let jump_ok = matches!(self.jump_instr, [0xeb, _, 0x90] | [0xe9, _, _]);
+        jump_ok
+            && self.signature == BOOT_SIGNATURE
+            && bp.bytes_per_sector == BYTES_PER_SECTOR as u16
+            && bp.sectors_per_cluster.is_power_of_two()
+            && bp.reserved_sector_count != 0
+            && bp.fat_count != 0
+            && bp._root_dir_entry_count == 0
+            && bp._sectors_per_fat_16 == 0
+            && bp.sectors_per_fat_32 != 0
+            && bp.total_sectors_32 != 0
+    }
+}
+
+// FAT32 keeps a hint of how many clusters are free, and where to look for the next one, so they
+// don't have to be worked out from the whole FAT every time the volume is mounted
+#[repr(C, packed)]
+struct FsInfo {
+    lead_signature: u32,
+    _reserved_1: [u8; 480],
+    struct_signature: u32,
+    free_count: u32,
+    next_free: u32,
+    _reserved_2: [u8; 12],
+    trail_signature: u32,
+}
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA550000;
+const FSINFO_UNKNOWN: u32 = 0xFFFFFFFF;
+
+// The FAT sector that was looked at last, already decoded into cluster entries.  Cluster chains
+// are usually laid out in order, so following one only has to go to the device once every 128
+// clusters.  Changes are made to the decoded entries and written out to every copy of the FAT
+// when another sector is needed or the volume is synced.
+struct FatCache {
+    sector: Cell<Option<u32>>,
+    dirty: Cell<bool>,
+    entries: RefCell<[u32; FAT_ENTRIES_PER_SECTOR]>,
+}
+
+impl FatCache {
+    fn new() -> FatCache {
+        FatCache {
+            sector: Cell::new(None),
+            dirty: Cell::new(false),
+            entries: RefCell::new([0; FAT_ENTRIES_PER_SECTOR]),
+        }
+    }
+}
+
+// What the FAT says about a cluster: either the next one in its chain, or one of the special
+// markers
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClusterLink {
+    Next(u32),
+    EndOfChain,
+    Bad,
+    Free,
+}
+
+pub struct Partition {
+    pub(crate) alloc_search_start: u32,
+    pub(crate) cluster_sector_mask: u8,
+    pub(crate) data_cluster_count: u32,
+    pub(crate) data_start_sector: u32,
+    pub(crate) fat_start_sector: u32,
+    pub(crate) free_cluster_count: Option<u32>,
+    pub(crate) log2_sectors_per_cluster: u8,
+    pub(crate) root_cluster: u32,
+    pub(crate) sectors_per_cluster: u8,
+    pub(crate) sectors_per_fat: u32,
+    pub(crate) volume_label: [u8; 11],
+    fat_cache: FatCache,
+    fs_info_sector: Option<u32>,
+    fs_info_dirty: Cell<bool>,
+}
+
+impl Partition {
+    // Check for a FAT32 boot sector at `sector`, which is how a device formatted without a
+    // partition table ("superfloppy") starts out
+    // returns: the number of sectors in the volume, or None if there's no boot sector there
+    pub(crate) fn probe<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        sector: u32,
+    ) -> Result<Option<u32>, FatError> {
+        let pbs_block = cache.read_sector_as::<_, PartitionBootSector>(&mut *device.borrow_mut(), sector)?;
+        let pbs = pbs_block.get();
+        if pbs.is_fat32() {
+            Ok(Some(pbs.bios_params.total_sectors_32))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub(crate) fn read<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        partition_entry: &PartitionEntry,
+    ) -> Result<Partition, FatError> {
+        let start_sector = partition_entry.start_sector();
+        let pbs_block = cache.read_sector_as::<_, PartitionBootSector>(&mut *device.borrow_mut(), start_sector)?;
+        let pbs = pbs_block.get();
+        let bp = &pbs.bios_params;
+
+        if bp.fat_count != FAT_COUNT || bp.bytes_per_sector != BYTES_PER_SECTOR as u16 {
+            return Err(FatError::CorruptPartition);
+        }
+
+        // The volume can be smaller than its partition, but not bigger, or the data area would
+        // run on into whatever comes after it
+        if bp.total_sectors_32 > partition_entry.total_sectors() {
+            return Err(FatError::CorruptPartition);
+        }
+
+        let mut log2_sectors_per_cluster: u8 = 0;
+        let mut i = 1;
+        while i != bp.sectors_per_cluster {
+            if i == 0 {
+                return Err(FatError::CorruptPartition);
+            }
+            log2_sectors_per_cluster += 1;
+            i <<= 1;
+        }
+        let sectors_per_fat = bp.sectors_per_fat_32;
+        let fat_start_sector = start_sector + bp.reserved_sector_count as u32;
+
+        // A volume too small to hold its own FATs can only be garbage
+        let data_cluster_count = match (bp.fat_count as u32)
+            .checked_mul(sectors_per_fat)
+            .and_then(|fat_sectors| fat_sectors.checked_add(bp.reserved_sector_count as u32))
+            .and_then(|metadata_sectors| bp.total_sectors_32.checked_sub(metadata_sectors))
+        {
+            Some(data_sectors) => data_sectors >> log2_sectors_per_cluster,
+            None => return Err(FatError::CorruptPartition),
+        };
+        let data_start_sector = fat_start_sector + (bp.fat_count as u32) * sectors_per_fat;
+
+        if data_cluster_count < 65525 {
+            return Err(FatError::UnsupportedVersion);
+        }
+
+        // The root directory is an ordinary cluster chain, and it's usually (but not always) the
+        // first one in the data area
+        let root_cluster = bp.fat_32_root_cluster;
+        if root_cluster < 2 || root_cluster > data_cluster_count + 1 {
+            return Err(FatError::CorruptPartition);
+        }
+
+        // 0 and 0xFFFF both mean there's no FSInfo sector
+        let fs_info_offset = bp.fat_32_fs_info_sector;
+        let fs_info_sector = if fs_info_offset == 0 || fs_info_offset >= bp.reserved_sector_count {
+            None
+        } else {
+            Some(start_sector + fs_info_offset as u32)
+        };
+
+        let mut partition = Partition {
+            alloc_search_start: 1,
+            cluster_sector_mask: bp.sectors_per_cluster - 1,
+            data_cluster_count,
+            data_start_sector,
+            fat_start_sector,
+            free_cluster_count: None, // Unknown number of free clusters
+            log2_sectors_per_cluster,
+            root_cluster,
+            sectors_per_cluster: bp.sectors_per_cluster,
+            sectors_per_fat,
+            volume_label: bp.volume_label,
+            fat_cache: FatCache::new(),
+            fs_info_sector: None,
+            fs_info_dirty: Cell::new(false),
+        };
+
+        // Let go of the boot sector before reading FSInfo, in case the cache only has one slot
+        drop(pbs_block);
+        if let Some(fs_info_sector) = fs_info_sector {
+            partition.read_fs_info(device, cache, fs_info_sector)?;
+        }
+        Ok(partition)
+    }
+
+    // FSInfo is only a hint, so if it doesn't look right it's ignored and never written back
+    fn read_fs_info<D: BlockDevice, const N: usize>(
+        &mut self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        fs_info_sector: u32,
+    ) -> Result<(), FatError> {
+        let fs_info_block = cache.read_sector_as::<_, FsInfo>(&mut *device.borrow_mut(), fs_info_sector)?;
+        let fs_info = fs_info_block.get();
+        if fs_info.lead_signature != FSINFO_LEAD_SIGNATURE
+            || fs_info.struct_signature != FSINFO_STRUCT_SIGNATURE
+            || fs_info.trail_signature != FSINFO_TRAIL_SIGNATURE
+        {
+            return Ok(());
+        }
+        self.fs_info_sector = Some(fs_info_sector);
+
+        // Either value can be 0xFFFFFFFF if it's unknown, or just be wrong if the volume was last
+        // written by something that didn't keep them up to date
+        let free_count = fs_info.free_count;
+        if free_count <= self.data_cluster_count {
+            self.free_cluster_count = Some(free_count);
+        }
+
+        // The hint is the last cluster that was allocated, same as alloc_search_start
+        let next_free = fs_info.next_free;
+        if next_free >= 2 && next_free <= self.last_cluster() {
+            self.alloc_search_start = next_free;
+        }
+        Ok(())
+    }
+
+    // Write the free cluster count and allocation hint back to FSInfo if they've changed
+    pub(crate) fn fs_info_sync<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+    ) -> Result<(), FatError> {
+        let fs_info_sector = match self.fs_info_sector {
+            Some(fs_info_sector) if self.fs_info_dirty.get() => fs_info_sector,
+            _ => return Ok(()),
+        };
+        let mut fs_info_block = cache.read_sector_as::<_, FsInfo>(&mut *device.borrow_mut(), fs_info_sector)?;
+        let fs_info = fs_info_block.get_mut();
+        fs_info.free_count = self.free_cluster_count.unwrap_or(FSINFO_UNKNOWN);
+        fs_info.next_free = if self.alloc_search_start >= 2 { self.alloc_search_start } else { FSINFO_UNKNOWN };
+        drop(fs_info_block);
+
+        // Only clean once the sector has actually reached the device, so a failed write gets
+        // tried again on the next sync
+        cache.sync_sector(&mut *device.borrow_mut(), fs_info_sector)?;
+        self.fs_info_dirty.set(false);
+        Ok(())
+    }
+
+    // Count the free clusters by going through the whole FAT
+    pub(crate) fn fat_count_free_clusters<D: BlockDevice, const N: usize>(
+        &mut self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+    ) -> Result<u32, FatError> {
+        let mut free_count = 0;
+        let mut cluster = 2;
+        while cluster <= self.last_cluster() {
+            let entries = self.fat_entries(device, cache, cluster)?;
+            let first = (cluster as usize) & (FAT_ENTRIES_PER_SECTOR - 1);
+            let count = min((FAT_ENTRIES_PER_SECTOR - first) as u32, self.last_cluster() - cluster + 1);
+            free_count += entries[first..first + count as usize]
+                .iter()
+                .filter(|entry| **entry & FAT32_ENTRY_MASK == 0)
+                .count() as u32;
+            cluster += count;
+        }
+
+        self.free_cluster_count = Some(free_count);
+        self.fs_info_dirty.set(true);
+        Ok(free_count)
+    }
+
+    pub(crate) fn fat_get_next_cluster<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        cluster: u32,
+    ) -> Result<ClusterLink, FatError> {
+        if cluster < 2 || cluster > self.last_cluster() {
+            return Err(FatError::InvalidCluster);
+        }
+
+        let entry = self.fat_entries(device, cache, cluster)?[(cluster as usize) & (FAT_ENTRIES_PER_SECTOR - 1)];
+        match entry & FAT32_ENTRY_MASK {
+            0 => Ok(ClusterLink::Free),
+            FAT32_BAD_CLUSTER => Ok(ClusterLink::Bad),
+            next if next >= FAT32_END_OF_CHAIN => Ok(ClusterLink::EndOfChain),
+            next if next < 2 || next > self.last_cluster() => Err(FatError::CorruptFat),
+            next => Ok(ClusterLink::Next(next)),
+        }
+    }
+
+    // Follow the cluster chain `count` links on from `cluster`; hops that stay within the cached
+    // FAT sector don't touch the device at all.  Returns EndOfChain if the chain runs out first.
+    pub(crate) fn fat_skip_clusters<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        mut cluster: u32,
+        count: u32,
+    ) -> Result<ClusterLink, FatError> {
+        // A chain can't be longer than the number of clusters on the volume, so anything that
+        // asks for more than that can only be going round in a loop
+        if count >= self.data_cluster_count {
+            return Err(FatError::CorruptFat);
+        }
+
+        // Shorter walks can still go round a loop, which is spotted by remembering a cluster and
+        // checking whether the chain comes back to it; the remembered cluster moves on after 1, 2,
+        // 4, ... hops, so any loop is caught within a couple of times round (Brent's algorithm)
+        let (mut seen, mut window, mut hops) = (cluster, 1, 0);
+        for _ in 0..count {
+            match self.fat_get_next_cluster(device, cache, cluster)? {
+                ClusterLink::Next(next) => cluster = next,
+                ClusterLink::EndOfChain => return Ok(ClusterLink::EndOfChain),
+                // Allocated chains never run into free or bad clusters
+                ClusterLink::Bad | ClusterLink::Free => return Err(FatError::CorruptFat),
+            }
+            if cluster == seen {
+                return Err(FatError::CorruptFat);
+            }
+            hops += 1;
+            if hops == window {
+                seen = cluster;
+                window <<= 1;
+                hops = 0;
+            }
+        }
+        Ok(ClusterLink::Next(cluster))
+    }
+
+    // Find `count` free clusters and link them onto the end of the chain that finishes at
+    // `last_cluster`, or start a new chain if it's 0.  Clusters that carry straight on from the
+    // chain are preferred, then the first run long enough to hold all of them, so files stay
+    // contiguous where possible.
+    // returns: the first of the new clusters
+    pub(crate) fn fat_allocate_clusters<D: BlockDevice, const N: usize>(
+        &mut self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        last_cluster: u32,
+        count: u32,
+    ) -> Result<u32, FatError> {
+        if count == 0 {
+            return Err(FatError::InvalidCluster);
+        } else if matches!(self.free_cluster_count, Some(free) if free < count) {
+            return Err(FatError::VolumeFull);
+        }
+
+        let mut first_cluster = 0;
+        let mut tail = last_cluster;
+        let mut remaining = count;
+        while remaining > 0 {
+            let hint = if tail != 0 { tail } else { self.alloc_search_start };
+            let (run_start, run_len) = match self.fat_find_free_run(device, cache, hint, remaining) {
+                Ok(run) => run,
+                // FSInfo said there was enough room but it was wrong; give back what's been taken
+                // so far, so the chain is left the way it was
+                Err(FatError::VolumeFull) if first_cluster != 0 => {
+                    self.fat_unlink_clusters(device, cache, last_cluster, first_cluster, count - remaining)?;
+                    return Err(FatError::VolumeFull);
+                },
+                Err(e) => return Err(e),
+            };
+            let run_end = run_start + run_len - 1;
+
+            // Terminate the new run before hooking it onto the chain, so the chain never points
+            // at clusters that aren't linked up yet
+            for cluster in run_start..run_end {
+                self.fat_set_entry(device, cache, cluster, cluster + 1)?;
+            }
+            self.fat_set_entry(device, cache, run_end, FAT32_END_OF_CHAIN)?;
+            if tail != 0 {
+                self.fat_set_entry(device, cache, tail, run_start)?;
+            }
+            if first_cluster == 0 {
+                first_cluster = run_start;
+            }
+
+            if let Some(free) = self.free_cluster_count.as_mut() {
+                *free = free.saturating_sub(run_len);
+            }
+            self.alloc_search_start = run_end;
+            self.fs_info_dirty.set(true);
+            tail = run_end;
+            remaining -= run_len;
+        }
+        Ok(first_cluster)
+    }
+
+    // Undo a partly finished allocation: cut the chain off at `last_cluster` again (if there was
+    // one), and free the `count` clusters that had been linked on after it
+    fn fat_unlink_clusters<D: BlockDevice, const N: usize>(
+        &mut self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        last_cluster: u32,
+        first_cluster: u32,
+        count: u32,
+    ) -> Result<(), FatError> {
+        if last_cluster != 0 {
+            self.fat_set_entry(device, cache, last_cluster, FAT32_END_OF_CHAIN)?;
+        }
+        let mut cluster = first_cluster;
+        for _ in 0..count {
+            let next = self.fat_get_next_cluster(device, cache, cluster)?;
+            self.fat_free_cluster(device, cache, cluster)?;
+            match next {
+                ClusterLink::Next(next) => cluster = next,
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    // Mark a single cluster as free; callers are responsible for unlinking it from its chain
+    pub(crate) fn fat_free_cluster<D: BlockDevice, const N: usize>(
+        &mut self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        cluster: u32,
+    ) -> Result<(), FatError> {
+        // A cluster that was free already mustn't be counted twice, or the free count would end
+        // up bigger than the volume
+        if self.fat_set_entry(device, cache, cluster, 0)? == 0 {
+            return Ok(());
+        }
+        if let Some(free) = self.free_cluster_count.as_mut() {
+            *free += 1;
+        }
+
+        // Start the next search from here so freed space gets reused
+        if cluster <= self.alloc_search_start {
+            self.alloc_search_start = cluster - 1;
+        }
+        self.fs_info_dirty.set(true);
+        Ok(())
+    }
+
+    // Write the cached FAT sector out to the device if it's been modified
+    pub(crate) fn fat_sync<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+    ) -> Result<(), FatError> {
+        let fat_sector = match self.fat_cache.sector.get() {
+            Some(fat_sector) if self.fat_cache.dirty.get() => fat_sector,
+            _ => return Ok(()),
+        };
+        let mut entries = self.fat_cache.entries.borrow_mut();
+
+        // Encode the entries back into on-disk byte order while they're written out, then decode
+        // them again so the cached copy stays usable
+        for entry in entries.iter_mut() {
+            *entry = entry.to_le();
+        }
+        let raw = unsafe { &*(&*entries as *const [u32; FAT_ENTRIES_PER_SECTOR] as *const SECTOR) };
+        let mut res = Ok(());
+        for fat in 0..FAT_COUNT as u32 {
+            res = res.and_then(|_| {
+                cache.write_sectors(&mut *device.borrow_mut(), fat_sector + fat * self.sectors_per_fat, raw)
+            });
+        }
+        for entry in entries.iter_mut() {
+            *entry = u32::from_le(*entry);
+        }
+        res?;
+
+        self.fat_cache.dirty.set(false);
+        Ok(())
+    }
+
+    // returns: the longest run of free clusters after `hint` (wrapping round to the start of the
+    // volume), stopping as soon as one `max_len` clusters long turns up
+    fn fat_find_free_run<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        hint: u32,
+        max_len: u32,
+    ) -> Result<(u32, u32), FatError> {
+        let (mut best_start, mut best_len) = (0, 0);
+        let (mut run_start, mut run_len) = (0, 0);
+        let mut cluster = hint;
+        for _ in 0..self.data_cluster_count {
+            cluster += 1;
+            if cluster < 2 || cluster > self.last_cluster() {
+                // Runs can't wrap round the end of the volume
+                cluster = 2;
+                run_len = 0;
+            }
+
+            let entry = self.fat_entries(device, cache, cluster)?[(cluster as usize) & (FAT_ENTRIES_PER_SECTOR - 1)];
+            if entry & FAT32_ENTRY_MASK != 0 {
+                run_len = 0;
+                continue;
+            }
+
+            if run_len == 0 {
+                run_start = cluster;
+            }
+            run_len += 1;
+            if run_len > best_len {
+                best_start = run_start;
+                best_len = run_len;
+                if best_len == max_len {
+                    break;
+                }
+            }
+        }
+
+        if best_len == 0 {
+            return Err(FatError::VolumeFull);
+        }
+        Ok((best_start, best_len))
+    }
+
+    // returns: what the entry was before, without the reserved bits
+    fn fat_set_entry<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        cluster: u32,
+        value: u32,
+    ) -> Result<u32, FatError> {
+        if cluster < 2 || cluster > self.last_cluster() {
+            return Err(FatError::InvalidCluster);
+        }
+
+        // The reserved top 4 bits have to be left as they are
+        let mut entries = self.fat_entries_mut(device, cache, cluster)?;
+        let entry = &mut entries[(cluster as usize) & (FAT_ENTRIES_PER_SECTOR - 1)];
+        let old_value = *entry & FAT32_ENTRY_MASK;
+        *entry = (*entry & !FAT32_ENTRY_MASK) | (value & FAT32_ENTRY_MASK);
+        Ok(old_value)
+    }
+
+    // returns: the decoded FAT sector holding the entry for `cluster`, reading it from the device
+    // if it isn't the one that's already cached
+    fn fat_entries<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        cluster: u32,
+    ) -> Result<Ref<'_, [u32; FAT_ENTRIES_PER_SECTOR]>, FatError> {
+        self.fat_load(device, cache, cluster)?;
+        Ok(self.fat_cache.entries.borrow())
+    }
+
+    // Same as fat_entries, but the sector gets written back to the device later
+    fn fat_entries_mut<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        cluster: u32,
+    ) -> Result<RefMut<'_, [u32; FAT_ENTRIES_PER_SECTOR]>, FatError> {
+        self.fat_load(device, cache, cluster)?;
+        self.fat_cache.dirty.set(true);
+        Ok(self.fat_cache.entries.borrow_mut())
+    }
+
+    fn fat_load<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        cluster: u32,
+    ) -> Result<(), FatError> {
+        let fat_sector = self.fat_start_sector + (cluster >> LOG2_FAT_ENTRIES_PER_SECTOR);
+        if self.fat_cache.sector.get() == Some(fat_sector) {
+            return Ok(());
+        }
+
+        // Any changes to the old sector have to reach the device before it's replaced
+        self.fat_sync(device, cache)?;
+
+        // Forget the old sector first so a failed read doesn't leave garbage marked valid
+        self.fat_cache.sector.set(None);
+        let mut entries = self.fat_cache.entries.borrow_mut();
+
+        // Read the raw sector straight into the entry array, then fix up the byte order in place
+        // (a no-op on little-endian targets)
+        let raw = unsafe { &mut *(&mut *entries as *mut [u32; FAT_ENTRIES_PER_SECTOR] as *mut SECTOR) };
+        cache.read_sectors(&mut *device.borrow_mut(), fat_sector, raw)?;
+        for entry in entries.iter_mut() {
+            *entry = u32::from_le(*entry);
+        }
+        self.fat_cache.sector.set(Some(fat_sector));
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub(crate) fn cluster_start_sector(&self, cluster: u32) -> u32 {
+        // Skip the two reserved clusters at the beginning
+        self.data_start_sector + ((cluster - 2) << self.log2_sectors_per_cluster)
+    }
+
+    #[inline(always)]
+    pub(crate) fn last_cluster(&self) -> u32 {
+        self.data_cluster_count + 1
+    }
+
+    #[inline(always)]
+    pub(crate) fn log2_bytes_per_cluster(&self) -> u8 {
+        // Operating in log space so multiplication becomes addition
+        self.log2_sectors_per_cluster + LOG2_BYTES_PER_SECTOR
+    }
+
+    #[inline(always)]
+    pub(crate) fn sector_of_cluster(&self, pos: u32) -> u32 {
+        // Divide by the number of sectors per cluster, and mask to restrict to the current cluster
+        (pos >> LOG2_BYTES_PER_SECTOR) & (self.cluster_sector_mask as u32)
+    }
+}
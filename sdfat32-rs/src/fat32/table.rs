@@ -0,0 +1,154 @@
+use super::{
+    cache::SectorCache,
+    gpt::{
+        Gpt,
+        GptPartitionInfo,
+        Guid,
+    },
+    mbr::{
+        Mbr,
+        PartitionInfo,
+        PartitionType,
+    },
+    partition::Partition,
+    FatError,
+};
+use crate::block_device::{
+    BlockDevice,
+    BlockDeviceRef,
+};
+
+
+// The GPT partition types that hold FAT volumes, as they're stored on disk
+const GUID_BASIC_DATA: Guid =
+    Guid([0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7]);
+const GUID_EFI_SYSTEM: Guid =
+    Guid([0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]);
+
+// A partition from whichever kind of partition table the device has; a device with no
+// partition table, just a volume starting at sector 0, gets one that covers its size in sectors
+#[derive(Clone, Copy)]
+pub enum PartitionEntry {
+    Mbr(PartitionInfo),
+    Gpt(GptPartitionInfo),
+    Superfloppy(u32),
+}
+
+impl PartitionEntry {
+    #[inline(always)]
+    pub fn start_sector(&self) -> u32 {
+        match self {
+            PartitionEntry::Mbr(info) => info.start_sector,
+            PartitionEntry::Gpt(info) => info.first_sector,
+            PartitionEntry::Superfloppy(_) => 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn total_sectors(&self) -> u32 {
+        match self {
+            PartitionEntry::Mbr(info) => info.total_sectors,
+            PartitionEntry::Gpt(info) => info.total_sectors(),
+            PartitionEntry::Superfloppy(total_sectors) => *total_sectors,
+        }
+    }
+
+    // Whether the partition type says this could hold a FAT volume; whether it really does isn't
+    // known until its boot sector is read
+    pub fn is_fat(&self) -> bool {
+        match self {
+            PartitionEntry::Mbr(info) => info.partition_type().is_fat(),
+            PartitionEntry::Gpt(info) => info.type_guid == GUID_BASIC_DATA || info.type_guid == GUID_EFI_SYSTEM,
+            PartitionEntry::Superfloppy(_) => true,
+        }
+    }
+}
+
+// Lists the partitions on a device without the caller having to care whether it has an MBR or
+// a GPT; a GPT is used if the MBR is just the protective one that goes in front of it.  Logical
+// partitions in an MBR's extended partition come after the four primary slots, so the first one
+// is number 4 (or 5 counting from 1, as most tools do).  A device with a FAT32 boot sector
+// instead of an MBR has a table with just the one volume in it.
+pub enum PartitionTable {
+    Mbr {
+        primary: [PartitionInfo; 4],
+        logical_count: u32,
+    },
+    Gpt(Gpt),
+    Superfloppy(u32),
+}
+
+impl PartitionTable {
+    pub fn read<D: BlockDevice, const N: usize>(
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+    ) -> Result<PartitionTable, FatError> {
+        // A boot sector has the same signature as an MBR, and its boot code would be read as
+        // partition entries, so it has to be ruled out first
+        if let Some(total_sectors) = Partition::probe(device, cache, 0)? {
+            return Ok(PartitionTable::Superfloppy(total_sectors));
+        }
+
+        let sector_count = match device.borrow_mut().sector_count() {
+            Ok(sector_count) => sector_count,
+            Err(_) => return Err(FatError::BlockDeviceFailed),
+        };
+        let primary = Mbr::read(device, cache, sector_count)?;
+        if primary.iter().any(|info| info.partition_type() == PartitionType::GptProtective) {
+            return Ok(PartitionTable::Gpt(Gpt::read_headers(device, cache, sector_count)?));
+        }
+
+        let logical_count = match primary.iter().find(|info| info.is_extended()) {
+            Some(extended) => Mbr::count_logical(device, cache, extended)?,
+            None => 0,
+        };
+        Ok(PartitionTable::Mbr { primary, logical_count })
+    }
+
+    // Number of slots in the table, including empty ones
+    pub fn len(&self) -> u32 {
+        match self {
+            PartitionTable::Mbr { primary, logical_count } => primary.len() as u32 + logical_count,
+            PartitionTable::Gpt(gpt) => gpt.entry_count,
+            PartitionTable::Superfloppy(_) => 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // returns: the partition in slot `index`, or None if that slot is empty
+    pub fn partition<D: BlockDevice, const N: usize>(
+        &self,
+        device: BlockDeviceRef<D>,
+        cache: &SectorCache<N>,
+        index: u32,
+    ) -> Result<Option<PartitionEntry>, FatError> {
+        match self {
+            PartitionTable::Mbr { primary, logical_count } => match primary.get(index as usize) {
+                Some(info) if info.ptype == 0 => Ok(None),
+                Some(info) => Ok(Some(PartitionEntry::Mbr(*info))),
+                None if index < self.len() => {
+                    // Logical partitions are looked up by walking the EBR chain again, rather than
+                    // keeping them all in RAM
+                    let extended = match primary.iter().find(|info| info.is_extended()) {
+                        Some(extended) => extended,
+                        None => return Err(FatError::BadPartitionNumber),
+                    };
+                    let logical_index = index - primary.len() as u32;
+                    match Mbr::read_logical(device, cache, extended, logical_index)? {
+                        Some(info) if logical_index < *logical_count => Ok(Some(PartitionEntry::Mbr(info))),
+                        _ => Err(FatError::CorruptMBR),
+                    }
+                },
+                None => Err(FatError::BadPartitionNumber),
+            },
+            PartitionTable::Gpt(gpt) => Ok(gpt.read_entry(device, cache, index)?.map(PartitionEntry::Gpt)),
+            PartitionTable::Superfloppy(total_sectors) if index == 0 => {
+                Ok(Some(PartitionEntry::Superfloppy(*total_sectors)))
+            },
+            PartitionTable::Superfloppy(_) => Err(FatError::BadPartitionNumber),
+        }
+    }
+}
@@ -2,26 +2,28 @@ use super::{
     DirEntry,
     FatError,
     File,
-    SdCardRef,
     Volume,
     LFN,
     SFN,
 };
-use avr_hal_generic::port::PinOps;
+use crate::block_device::{
+    BlockDevice,
+    BlockDeviceRef,
+};
 use core::mem;
 
 
-pub(crate) struct DirectoryIterator<'d, 'v: 'd, 's: 'v, CSPIN: PinOps> {
+pub(crate) struct DirectoryIterator<'d, 'v: 'd, 'b: 'v, D: BlockDevice, const N: usize> {
     dir: &'d mut File,
     lfn_checksum: u8,
     lfn_size: usize,
     lfn_next: usize,
     sfn_attr: u8,
-    sdcard: SdCardRef<'s, CSPIN>,
-    vol: &'v Volume,
+    device: BlockDeviceRef<'b, D>,
+    vol: &'v Volume<N>,
 }
 
-impl<CSPIN: PinOps> Iterator for DirectoryIterator<'_, '_, '_, CSPIN> {
+impl<D: BlockDevice, const N: usize> Iterator for DirectoryIterator<'_, '_, '_, D, N> {
     type Item = Result<DirEntry, FatError>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -30,8 +32,10 @@ impl<CSPIN: PinOps> Iterator for DirectoryIterator<'_, '_, '_, CSPIN> {
             return Some(Err(FatError::InvalidPosition));
         }
         loop {
-            match self.vol.load_sector_for_file::<_, [SFN; 16]>(self.sdcard, self.dir) {
-                Ok((entries_raw, sector_pos)) => {
+            match self.vol.load_sector_for_file::<_, [SFN; 16]>(self.device, self.dir) {
+                // The directory's cluster chain ended without an end-of-directory marker
+                Ok(None) => return None,
+                Ok(Some((entries_raw, sector_pos))) => {
                     let entry_index = sector_pos >> 5; // Divide by 32 to get the index into the array
                     let entry = entries_raw.get()[entry_index];
 
@@ -105,12 +109,12 @@ impl<CSPIN: PinOps> Iterator for DirectoryIterator<'_, '_, '_, CSPIN> {
     }
 }
 
-impl Volume {
-    pub(crate) fn dir_next<'d, 'v: 'd, 's: 'v, CSPIN: PinOps>(
+impl<const N: usize> Volume<N> {
+    pub(crate) fn dir_next<'d, 'v: 'd, 'b: 'v, D: BlockDevice>(
         &'v self,
-        sdcard: SdCardRef<'s, CSPIN>,
+        device: BlockDeviceRef<'b, D>,
         dir: &'d mut File,
-    ) -> DirectoryIterator<'s, 'd, 'v, CSPIN> {
+    ) -> DirectoryIterator<'b, 'd, 'v, D, N> {
         // Callers should ensure that `dir` is a directory
         DirectoryIterator {
             dir,
@@ -118,7 +122,7 @@ impl Volume {
             lfn_next: 0,
             lfn_size: 0,
             sfn_attr: 0,
-            sdcard,
+            device,
             vol: self,
         }
     }
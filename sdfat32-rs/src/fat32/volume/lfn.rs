@@ -2,12 +2,16 @@ use super::{
     DirEntry,
     FatError,
     File,
-    SdCardRef,
     Volume,
     LFN,
 };
-use crate::fat32::constants::*;
-use avr_hal_generic::port::PinOps;
+use crate::{
+    block_device::{
+        BlockDevice,
+        BlockDeviceRef,
+    },
+    fat32::constants::*,
+};
 
 const FNAME_FLAG_TRUNCATED: u8 = 0x01;
 const FNAME_FLAG_MIXED_CASE: u8 = 0x02;
@@ -19,13 +23,11 @@ pub(crate) struct Fname<'a> {
     path: &'a [u8],
     path_end: usize,
     flags: u8,
-    trunc_pos: usize,
     sfn: [u8; 11],
 }
 
 impl<'a> Fname<'a> {
     fn new(path: &'a [u8], path_end: usize) -> Fname<'a> {
-        let mut flags: u8 = 0;
         let mut trunc_pos: usize = 0;
         let mut sfn: [u8; 11] = [SPACE; 11];
         let (mut sfn_pos, mut sfn_end) = (0usize, 7usize);
@@ -66,7 +68,7 @@ impl<'a> Fname<'a> {
                         if (c & 0xc0) == 0x80 {
                             continue;
                         }
-                        c = '_' as u8;
+                        c = b'_';
                     }
 
                     if sfn_pos > sfn_end {
@@ -99,14 +101,18 @@ impl<'a> Fname<'a> {
             path_pos += 1;
         }
 
-        if is83 {
-            flags = if lc_parts != uc_parts { FNAME_FLAG_MIXED_CASE } else { lc_parts };
+        let flags = if is83 {
+            if lc_parts != uc_parts {
+                FNAME_FLAG_MIXED_CASE
+            } else {
+                lc_parts
+            }
         } else {
-            flags = FNAME_FLAG_TRUNCATED;
-            sfn[trunc_pos] = '~' as u8;
-            sfn[trunc_pos + 1] = '1' as u8;
-        }
-        Fname { path, path_end, flags, trunc_pos, sfn }
+            sfn[trunc_pos] = b'~';
+            sfn[trunc_pos + 1] = b'1';
+            FNAME_FLAG_TRUNCATED
+        };
+        Fname { path, path_end, flags, sfn }
     }
 
     pub(crate) fn checksum(&self) -> u8 {
@@ -119,7 +125,7 @@ impl<'a> Fname<'a> {
 
     #[inline(always)]
     fn lfn_entry_count(&self) -> usize {
-        (self.path_end + 12) / 13
+        self.path_end.div_ceil(13)
     }
 }
 
@@ -149,20 +155,20 @@ pub(crate) fn parse_path_name<'a>(path: &'a [u8]) -> Result<(Fname<'a>, usize),
     Ok((Fname::new(path, end), pos))
 }
 
-impl Volume {
-    pub(crate) fn open_file_from_lfn<CSPIN: PinOps>(
+impl<const N: usize> Volume<N> {
+    pub(crate) fn open_file_from_lfn<D: BlockDevice>(
         &self,
-        sdcard: SdCardRef<CSPIN>,
+        device: BlockDeviceRef<D>,
         dir: &mut File,
         fname: &Fname,
         flags: u8,
     ) -> Result<File, FatError> {
         self.check_dir(dir)?;
-        self.seek(sdcard, dir, 0)?;
+        self.seek(device, dir, 0)?;
 
         // Try to determine whether the current DirEntry matches up with the provided filename
         let mut lfn_match = true;
-        for maybe_entry in self.dir_next(sdcard, dir) {
+        for maybe_entry in self.dir_next(device, dir) {
             let entry = maybe_entry?;
             match entry {
                 DirEntry::Long(lfn, entry_count, _) => {
@@ -198,7 +204,7 @@ impl Volume {
 fn compare_lfn_name_segment(lfn: &LFN, fname: &Fname) -> bool {
     for i in 0..13 {
         let c = (lfn.get_char(i) as char).to_ascii_uppercase();
-        let fname_pos = (lfn.sequence_num() as usize - 1) * 13 + i; // LFN entries are 1-indexed
+        let fname_pos = (lfn.sequence_num() - 1) * 13 + i; // LFN entries are 1-indexed
         let fname_c = (fname.path[fname_pos] as char).to_ascii_uppercase();
 
         // LFN entries and fname entries should be zero-terminated, so check one past the end
@@ -208,26 +214,25 @@ fn compare_lfn_name_segment(lfn: &LFN, fname: &Fname) -> bool {
             return false;
         }
     }
-    return true;
+    true
 }
 
 #[inline(always)]
 fn sfn_reserved_char(c: u8) -> bool {
     // ", [, \, ], |, *+,./, :;<=>?,
-    return c < 0x20
-        || c > 0x7f
+    !(0x20..=0x7f).contains(&c)
         || c == 0x5b
         || c == 0x5c
         || c == 0x5d
         || c == 0x7c
-        || (0x2a <= c && c <= 0x2f && c != 0x2d)
-        || (0x3a <= c && c <= 0x3f);
+        || ((0x2a..=0x2f).contains(&c) && c != 0x2d)
+        || (0x3a..=0x3f).contains(&c)
 }
 
 #[inline(always)]
 fn lfn_reserved_char(c: u8) -> bool {
     // ", *, /, :, <, >, ?, \, |
-    return c < 0x20
+    c < 0x20
         || c == 0x22
         || c == 0x2a
         || c == 0x2f
@@ -236,5 +241,5 @@ fn lfn_reserved_char(c: u8) -> bool {
         || c == 0x3e
         || c == 0x3f
         || c == 0x5c
-        || c == 0x7c;
+        || c == 0x7c
 }
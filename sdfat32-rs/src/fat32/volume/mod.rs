@@ -2,6 +2,10 @@ mod dir_iter;
 mod lfn;
 
 use super::{
+    cache::{
+        Block,
+        SectorCache,
+    },
     constants::*,
     dir_entry::{
         DirEntry,
@@ -9,16 +13,20 @@ use super::{
         SFN,
     },
     file::File,
-    mbr,
-    partition::Partition,
+    partition::{
+        ClusterLink,
+        Partition,
+    },
+    table::{
+        PartitionEntry,
+        PartitionTable,
+    },
     FatError,
 };
-use crate::sdcard::{
-    Block,
-    SdCardRef,
-    DATA_BUFFER,
+use crate::block_device::{
+    BlockDevice,
+    BlockDeviceRef,
 };
-use avr_hal_generic::port::PinOps;
 use core::{
     cmp::min,
     convert::TryInto,
@@ -26,23 +34,70 @@ use core::{
 use lfn::parse_path_name;
 
 
-pub struct Volume {
+// Each volume keeps its own cache of N sectors (N * BLOCK_SIZE bytes of RAM, on top of the
+// partition's FAT sector), so pick N to suit the board: one or two on an ATmega328p, more if
+// there's RAM to spare
+pub struct Volume<const N: usize> {
     pub partition: Partition,
     id: u8,
+    cache: SectorCache<N>,
 }
 
-impl Volume {
-    pub fn open_volume<CSPIN: PinOps>(
-        sdcard: SdCardRef<CSPIN>,
+impl<const N: usize> Volume<N> {
+    // The cache is the one the partition table was read with, if it was; the volume keeps it
+    pub fn open_volume<D: BlockDevice>(
+        device: BlockDeviceRef<D>,
+        cache: SectorCache<N>,
         part_id: u8,
-        part_info: &mbr::PartitionInfo,
-    ) -> Result<Volume, FatError> {
+        partition_entry: &PartitionEntry,
+    ) -> Result<Volume<N>, FatError> {
+        if !partition_entry.is_fat() {
+            return Err(FatError::NotFatPartition);
+        }
         Ok(Volume {
-            partition: Partition::read(sdcard, part_info)?,
+            partition: Partition::read(device, &cache, partition_entry)?,
             id: part_id,
+            cache,
         })
     }
 
+    // Open the first partition that has a FAT32 volume on it, going through the partition table
+    // in order; a device without a partition table is tried as a single volume
+    pub fn mount_first_fat<D: BlockDevice>(device: BlockDeviceRef<D>, part_id: u8) -> Result<Volume<N>, FatError> {
+        let cache = SectorCache::new();
+        let table = PartitionTable::read(device, &cache)?;
+        let mut last_error = FatError::BadPartitionNumber;
+        for index in 0..table.len() {
+            let result = match table.partition(device, &cache, index) {
+                Ok(Some(partition_entry)) if !partition_entry.is_fat() => Err(FatError::NotFatPartition),
+                Ok(Some(partition_entry)) => Partition::read(device, &cache, &partition_entry),
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(partition) => return Ok(Volume { partition, id: part_id, cache }),
+                // No point trying the rest if the device itself isn't working
+                Err(FatError::BlockDeviceFailed) => return Err(FatError::BlockDeviceFailed),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    // Put a new, empty FAT32 volume on a partition and open it.  The label is padded with
+    // spaces, the same as a short file name; "NO NAME    " means there isn't one.
+    pub fn format<D: BlockDevice>(
+        device: BlockDeviceRef<D>,
+        cache: SectorCache<N>,
+        part_id: u8,
+        partition_entry: &PartitionEntry,
+        volume_label: &[u8; 11],
+        volume_serial: u32,
+    ) -> Result<Volume<N>, FatError> {
+        Partition::format(device, &cache, partition_entry, volume_label, volume_serial)?;
+        Self::open_volume(device, cache, part_id, partition_entry)
+    }
+
     pub fn close(&self, file: &mut File) -> Result<(), FatError> {
         self.check_file(file)?;
         // TODO sync
@@ -50,20 +105,21 @@ impl Volume {
         Ok(())
     }
 
-    pub fn ls<CSPIN: PinOps, T>(
+    #[allow(clippy::too_many_arguments)]
+    pub fn ls<D: BlockDevice, T>(
         &self,
-        sdcard: SdCardRef<CSPIN>,
+        device: BlockDeviceRef<D>,
         dir: &mut File,
         show_hidden: bool,
         depth: u16,
         depth_limit: u16,
         context: &mut T,
-        mut func: impl FnMut(&DirEntry, u16, &mut T) -> () + Copy,
+        mut func: impl FnMut(&DirEntry, u16, &mut T) + Copy,
     ) -> Result<(), FatError> {
         self.check_dir(dir)?;
-        self.seek(sdcard, dir, 0)?;
+        self.seek(device, dir, 0)?;
 
-        for maybe_entry in self.dir_next(sdcard, dir) {
+        for maybe_entry in self.dir_next(device, dir) {
             let entry = maybe_entry?;
             if entry.is_deleted() || (entry.is_hidden() && !show_hidden) {
                 continue;
@@ -72,7 +128,7 @@ impl Volume {
             if let DirEntry::Short(sfn, _) = entry {
                 if depth_limit > 0 && sfn.is_directory() && !sfn.is_self_or_parent() {
                     let mut d = self.open(&sfn, O_RDONLY);
-                    self.ls(sdcard, &mut d, show_hidden, depth + 1, depth_limit - 1, context, func)?;
+                    self.ls(device, &mut d, show_hidden, depth + 1, depth_limit - 1, context, func)?;
                 }
             }
         }
@@ -80,13 +136,18 @@ impl Volume {
     }
 
     pub fn open(&self, entry: &SFN, flags: u8) -> File {
+        // ".." entries in directories just below the root point at cluster 0, not at the root's
+        // real cluster
+        if entry.is_directory() && entry.first_cluster() == 0 {
+            return self.open_root(flags);
+        }
         File::open(self.id, entry, flags)
     }
 
-    pub fn open_by_name<'a, CSPIN: PinOps>(
+    pub fn open_by_name<D: BlockDevice>(
         &self,
-        sdcard: SdCardRef<CSPIN>,
-        filename: &'a [u8],
+        device: BlockDeviceRef<D>,
+        filename: &[u8],
         flags: u8,
     ) -> Result<File, FatError> {
         let mut pos = 0;
@@ -101,20 +162,20 @@ impl Volume {
             let (fname, p) = parse_path_name(&filename[pos..filename.len()])?;
             pos += p;
             if pos >= filename.len() || filename[pos] == 0 {
-                return self.open_file_from_lfn(sdcard, &mut current_dir, &fname, flags);
+                return self.open_file_from_lfn(device, &mut current_dir, &fname, flags);
             }
-            let next_dir = self.open_file_from_lfn(sdcard, &mut current_dir, &fname, O_RDONLY)?;
+            let next_dir = self.open_file_from_lfn(device, &mut current_dir, &fname, O_RDONLY)?;
             current_dir = next_dir;
         }
     }
 
     pub fn open_root(&self, flags: u8) -> File {
-        File::open_root(self.id, flags)
+        File::open_root(self.id, self.partition.root_cluster, flags)
     }
 
-    pub fn read<CSPIN: PinOps>(
+    pub fn read<D: BlockDevice>(
         &mut self,
-        sdcard: SdCardRef<CSPIN>,
+        device: BlockDeviceRef<D>,
         file: &mut File,
         buffer: &mut [u8],
     ) -> Result<usize, FatError> {
@@ -132,15 +193,79 @@ impl Volume {
 
         let mut remainder = num_bytes;
         while remainder > 0 {
-            let (sector_raw, sector_pos) = self.load_sector_for_file::<_, SECTOR>(sdcard, file)?;
-            let n: usize = if sector_pos != 0 || remainder < BYTES_PER_SECTOR {
-                // Safe to do this cast because the max value is BYTES_PER_SECTOR
-                min(BYTES_PER_SECTOR - sector_pos, remainder)
+            let n: usize = if file.pos & (SECTOR_MASK as u32) == 0 && remainder >= BYTES_PER_SECTOR {
+                // Whole sectors can be read straight into the caller's buffer in one go
+                let (sector_index, sector_count) =
+                    match self.sector_run_for_file(device, file, (remainder >> LOG2_BYTES_PER_SECTOR) as u32)? {
+                        Some(run) => run,
+                        None => break, // The cluster chain is shorter than the file claims to be
+                    };
+                let n = (sector_count as usize) << LOG2_BYTES_PER_SECTOR;
+                self.cache
+                    .read_sectors(&mut *device.borrow_mut(), sector_index, &mut buffer[buf_pos..buf_pos + n])?;
+                self.advance_cluster_for_run(file, sector_count);
+                n
             } else {
-                BYTES_PER_SECTOR
+                let (sector_raw, sector_pos) = match self.load_sector_for_file::<_, SECTOR>(device, file)? {
+                    Some(sector) => sector,
+                    None => break,
+                };
+                // Safe to do this cast because the max value is BYTES_PER_SECTOR
+                let n = min(BYTES_PER_SECTOR - sector_pos, remainder);
+                buffer[buf_pos..buf_pos + n].copy_from_slice(&sector_raw.get()[sector_pos..sector_pos + n]);
+                n
             };
 
-            buffer[buf_pos..buf_pos + n].copy_from_slice(&sector_raw.get()[sector_pos..sector_pos + n]);
+            buf_pos += n;
+            file.pos += n as u32;
+            remainder -= n;
+        }
+
+        Ok(num_bytes - remainder)
+    }
+
+    pub fn write<D: BlockDevice>(
+        &mut self,
+        device: BlockDeviceRef<D>,
+        file: &mut File,
+        buffer: &[u8],
+    ) -> Result<usize, FatError> {
+        self.check_file(file)?;
+        if !file.is_writable() {
+            return Err(FatError::WriteError);
+        }
+
+        let mut buf_pos: usize = 0;
+
+        // Files can't grow yet, so writes stop at the end of the file
+        let bytes_to_eof = file.size() - file.pos;
+        let num_bytes: usize =
+            if buffer.len() as u32 > bytes_to_eof { bytes_to_eof.try_into().unwrap() } else { buffer.len() };
+
+        let mut remainder = num_bytes;
+        while remainder > 0 {
+            let n: usize = if file.pos & (SECTOR_MASK as u32) == 0 && remainder >= BYTES_PER_SECTOR {
+                // Whole sectors can be written straight from the caller's buffer in one go, which
+                // the SD card does with a single multi-block write
+                let (sector_index, sector_count) =
+                    match self.sector_run_for_file(device, file, (remainder >> LOG2_BYTES_PER_SECTOR) as u32)? {
+                        Some(run) => run,
+                        None => break,
+                    };
+                let n = (sector_count as usize) << LOG2_BYTES_PER_SECTOR;
+                self.cache.write_sectors(&mut *device.borrow_mut(), sector_index, &buffer[buf_pos..buf_pos + n])?;
+                self.advance_cluster_for_run(file, sector_count);
+                n
+            } else {
+                // Partial sectors go through the cache and get written back later
+                let (mut sector_raw, sector_pos) = match self.load_sector_for_file::<_, SECTOR>(device, file)? {
+                    Some(sector) => sector,
+                    None => break,
+                };
+                let n = min(BYTES_PER_SECTOR - sector_pos, remainder);
+                sector_raw.get_mut()[sector_pos..sector_pos + n].copy_from_slice(&buffer[buf_pos..buf_pos + n]);
+                n
+            };
 
             buf_pos += n;
             file.pos += n as u32;
@@ -150,7 +275,7 @@ impl Volume {
         Ok(num_bytes - remainder)
     }
 
-    pub fn seek<CSPIN: PinOps>(&self, sdcard: SdCardRef<CSPIN>, file: &mut File, pos: u32) -> Result<(), FatError> {
+    pub fn seek<D: BlockDevice>(&self, device: BlockDeviceRef<D>, file: &mut File, pos: u32) -> Result<(), FatError> {
         self.check_file(file)?;
         if !file.is_open() {
             return Err(FatError::FileClosed);
@@ -160,25 +285,35 @@ impl Volume {
             return Err(FatError::SeekError);
         }
 
+        // file.cluster is the cluster holding the byte just before file.pos, or the first cluster
+        // if file.pos is 0
         let old_cluster = file.cluster;
         match (|| {
+            if pos == 0 {
+                file.cluster = file.start_cluster;
+                return Ok(());
+            }
+
             let mut cluster_idx_new = (pos - 1) >> self.partition.log2_bytes_per_cluster();
             if file.is_contiguous() {
                 file.cluster = file.start_cluster + cluster_idx_new;
                 return Ok(());
             }
 
-            let cluster_idx_cur = (file.pos - 1) >> self.partition.log2_bytes_per_cluster();
-            if cluster_idx_new < cluster_idx_cur || file.pos == 0 {
-                file.cluster = if file.is_root() { ROOT_CLUSTER } else { file.start_cluster };
+            if file.pos == 0 || cluster_idx_new < (file.pos - 1) >> self.partition.log2_bytes_per_cluster() {
+                file.cluster = file.start_cluster;
             } else {
-                cluster_idx_new -= cluster_idx_cur;
+                cluster_idx_new -= (file.pos - 1) >> self.partition.log2_bytes_per_cluster();
             }
 
-            for _ in 0..cluster_idx_new {
-                file.cluster = self.partition.fat_get_next_cluster(sdcard, file.cluster)?;
+            match self.partition.fat_skip_clusters(device, &self.cache, file.cluster, cluster_idx_new)? {
+                ClusterLink::Next(cluster) => {
+                    file.cluster = cluster;
+                    Ok(())
+                },
+                // Past the end of a directory, or a file whose chain is shorter than its size
+                _ => Err(FatError::SeekError),
             }
-            Ok(())
         })() {
             Ok(()) => {
                 file.pos = pos;
@@ -191,6 +326,109 @@ impl Volume {
         }
     }
 
+    // Write any modified sectors in the buffer cache back to the device
+    pub fn sync<D: BlockDevice>(&self, device: BlockDeviceRef<D>) -> Result<(), FatError> {
+        self.partition.fat_sync(device, &self.cache)?;
+        self.partition.fs_info_sync(device, &self.cache)?;
+        self.cache.sync(&mut *device.borrow_mut())
+    }
+
+    // Sync everything before the volume goes away, e.g. before the card is removed
+    pub fn unmount<D: BlockDevice>(self, device: BlockDeviceRef<D>) -> Result<(), FatError> {
+        self.sync(device)
+    }
+
+    // Size of the data area in bytes
+    pub fn total_space(&self) -> u64 {
+        (self.partition.data_cluster_count as u64) << self.partition.log2_bytes_per_cluster()
+    }
+
+    // Free space in bytes, if it's known; it comes from FSInfo, so it's None if the volume
+    // doesn't have FSInfo or it didn't have a sensible free count
+    pub fn free_space(&self) -> Option<u64> {
+        self.partition
+            .free_cluster_count
+            .map(|free| (free as u64) << self.partition.log2_bytes_per_cluster())
+    }
+
+    // Work out the free space from the FAT itself, for when free_space() is None or FSInfo
+    // might be out of date.  This reads the whole FAT, so it can take a while on a big card;
+    // the result is kept and written back to FSInfo on the next sync.
+    pub fn scan_free_space<D: BlockDevice>(&mut self, device: BlockDeviceRef<D>) -> Result<u64, FatError> {
+        let free = self.partition.fat_count_free_clusters(device, &self.cache)?;
+        Ok((free as u64) << self.partition.log2_bytes_per_cluster())
+    }
+
+    // Allocate `count` clusters and link them onto the end of the chain that finishes at
+    // `last_cluster`, or start a new chain if it's 0.  The new clusters aren't cleared, and the
+    // free count and allocation hint reach FSInfo on the next sync.
+    // returns: the first of the new clusters
+    pub fn allocate_clusters<D: BlockDevice>(
+        &mut self,
+        device: BlockDeviceRef<D>,
+        last_cluster: u32,
+        count: u32,
+    ) -> Result<u32, FatError> {
+        self.partition.fat_allocate_clusters(device, &self.cache, last_cluster, count)
+    }
+
+    // Free every cluster in the chain starting at `first_cluster`, and let the device know their
+    // contents aren't needed any more.  Whatever pointed at the chain (a directory entry or the
+    // cluster before it) has to be updated by the caller.
+    pub fn free_cluster_chain<D: BlockDevice>(
+        &mut self,
+        device: BlockDeviceRef<D>,
+        first_cluster: u32,
+    ) -> Result<(), FatError> {
+        let mut cluster = first_cluster;
+        let (mut run_start, mut run_len) = (first_cluster, 0);
+
+        // Bounded by the number of clusters on the volume in case the chain loops back on itself.
+        // Where the chain goes is checked before each cluster is freed, so a loop stops when it
+        // comes back round to a cluster that's been freed already, rather than freeing it again.
+        for _ in 0..self.partition.data_cluster_count {
+            let next = match self.partition.fat_get_next_cluster(device, &self.cache, cluster)? {
+                ClusterLink::Bad | ClusterLink::Free => return Err(FatError::CorruptFat),
+                next => next,
+            };
+            self.partition.fat_free_cluster(device, &self.cache, cluster)?;
+
+            // Consecutive clusters are discarded together
+            if cluster == run_start + run_len {
+                run_len += 1;
+            } else {
+                self.discard_clusters(device, run_start, run_len)?;
+                run_start = cluster;
+                run_len = 1;
+            }
+
+            match next {
+                ClusterLink::Next(next) => cluster = next,
+                _ => return self.discard_clusters(device, run_start, run_len),
+            }
+        }
+        Err(FatError::CorruptFat)
+    }
+
+    // Discard the data in a run of consecutive clusters once they've been freed, so the device can
+    // erase them in the background instead of when they're next written.  The FAT has to say
+    // they're free on the device first; if the power goes before it does, the chain would still
+    // be there but its data wouldn't.
+    fn discard_clusters<D: BlockDevice>(
+        &self,
+        device: BlockDeviceRef<D>,
+        start_cluster: u32,
+        cluster_count: u32,
+    ) -> Result<(), FatError> {
+        self.partition.fat_sync(device, &self.cache)?;
+        self.partition.fs_info_sync(device, &self.cache)?;
+        self.cache.erase_sectors(
+            &mut *device.borrow_mut(),
+            self.partition.cluster_start_sector(start_cluster),
+            cluster_count << self.partition.log2_sectors_per_cluster,
+        )
+    }
+
     #[inline(always)]
     fn check_dir(&self, dir: &File) -> Result<(), FatError> {
         self.check_file(dir)?;
@@ -209,14 +447,59 @@ impl Volume {
     }
 
     // returns: the position in the sector corresponding to the file.pos
-    // (guaranteed to be at most BYTES_PER_SECTOR, so usize is fine)
-    fn load_sector_for_file<CSPIN: PinOps, T>(
+    // (guaranteed to be at most BYTES_PER_SECTOR, so usize is fine), or None if file.pos is past
+    // the end of the cluster chain
+    fn load_sector_for_file<D: BlockDevice, T>(
         &self,
-        sdcard: SdCardRef<CSPIN>,
+        device: BlockDeviceRef<D>,
         file: &mut File,
-    ) -> Result<(Block<T>, usize), FatError> {
+    ) -> Result<Option<(Block<'_, T>, usize)>, FatError> {
         // Unchecked; we assume that the file belongs to this volume and is readable
         let sector_pos = (file.pos & (SECTOR_MASK as u32)) as usize;
+        let sector_index = match self.sector_for_file(device, file)? {
+            Some(sector_index) => sector_index,
+            None => return Ok(None),
+        };
+        let sector = self.cache.read_sector_as::<_, T>(&mut *device.borrow_mut(), sector_index)?;
+        Ok(Some((sector, sector_pos)))
+    }
+
+    // returns: the first device sector for file.pos and the number of consecutive sectors (up to
+    // max_sectors) that can be transferred from there; the sectors must be consecutive on the
+    // device, so they either have to be in the same cluster or anywhere in a contiguous file
+    fn sector_run_for_file<D: BlockDevice>(
+        &self,
+        device: BlockDeviceRef<D>,
+        file: &mut File,
+        max_sectors: u32,
+    ) -> Result<Option<(u32, u32)>, FatError> {
+        let sector_index = match self.sector_for_file(device, file)? {
+            Some(sector_index) => sector_index,
+            None => return Ok(None),
+        };
+        let sector_count = if file.is_file() && file.is_contiguous() {
+            max_sectors
+        } else {
+            min(max_sectors, self.partition.sectors_per_cluster as u32 - self.partition.sector_of_cluster(file.pos))
+        };
+        Ok(Some((sector_index, sector_count)))
+    }
+
+    #[inline(always)]
+    fn advance_cluster_for_run(&self, file: &mut File, sector_count: u32) {
+        // Leave file.cluster pointing at the cluster containing the last byte transferred
+        let sector_of_cluster = self.partition.sector_of_cluster(file.pos);
+        file.cluster += (sector_of_cluster + sector_count - 1) >> self.partition.log2_sectors_per_cluster;
+    }
+
+    // returns: the device sector containing file.pos, moving file.cluster along the cluster chain
+    // if file.pos is at the start of a new cluster, or None if the chain ends before file.pos
+    fn sector_for_file<D: BlockDevice>(
+        &self,
+        device: BlockDeviceRef<D>,
+        file: &mut File,
+    ) -> Result<Option<u32>, FatError> {
+        let sector_pos = file.pos & (SECTOR_MASK as u32);
         let sector_of_cluster = self.partition.sector_of_cluster(file.pos);
 
         // This is the start of a new cluster, but we don't know which one yet
@@ -224,13 +507,18 @@ impl Volume {
             if file.is_file() && file.is_contiguous() {
                 file.cluster += 1;
             } else {
-                file.cluster = self.partition.fat_get_next_cluster(sdcard, file.cluster)?;
+                // Directories don't have a size to stop at, so a chain that's longer than the
+                // volume has clusters must loop back on itself
+                if file.pos >> self.partition.log2_bytes_per_cluster() >= self.partition.data_cluster_count {
+                    return Err(FatError::CorruptFat);
+                }
+                file.cluster = match self.partition.fat_get_next_cluster(device, &self.cache, file.cluster)? {
+                    ClusterLink::Next(cluster) => cluster,
+                    ClusterLink::EndOfChain => return Ok(None),
+                    ClusterLink::Bad | ClusterLink::Free => return Err(FatError::CorruptFat),
+                };
             }
         }
-        let sector_index = self.partition.cluster_start_sector(file.cluster) + sector_of_cluster;
-        match sdcard.borrow_mut().read_sector_as::<T>(DATA_BUFFER, sector_index) {
-            Ok(sector) => Ok((sector, sector_pos)),
-            Err(e) => Err(FatError::from(e)),
-        }
+        Ok(Some(self.partition.cluster_start_sector(file.cluster) + sector_of_cluster))
     }
 }
@@ -22,10 +22,22 @@ where
     Ok(())
 }
 
+// Each byte as two hex digits, in the order given and with no prefix
+pub(crate) fn hexfmt_bytes<W>(serial: &mut Formatter<W>, bytes: &[u8]) -> Result<(), W::Error>
+where
+    W: uWrite + ?Sized,
+{
+    for b in bytes.iter() {
+        serial.write_char(hexfmt_u8(b >> 4) as char)?;
+        serial.write_char(hexfmt_u8(b & 0x0f) as char)?;
+    }
+    Ok(())
+}
+
 fn hexfmt_u8(n: u8) -> u8 {
     match n & 0xf {
-        v if v <= 9 => (v as u8) + 48,
-        v if v > 9 => (v as u8) + 87,
+        v if v <= 9 => v + 48,
+        v if v > 9 => v + 87,
         _ => 63, // '?'
     }
 }
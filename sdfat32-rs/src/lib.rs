@@ -1,7 +1,14 @@
 #![no_std]
-#![feature(llvm_asm)]
+#![cfg_attr(target_arch = "avr", feature(llvm_asm))]
 #![allow(deprecated)] // llvm_asm!
+// The on-disk structures and SD registers keep the names the specs give them (SFN, CSD, ...)
+#![allow(clippy::upper_case_acronyms)]
 
+// The simulated SD card is only for testing on a host machine
+#[cfg(feature = "sim")]
+extern crate std;
+
+pub mod block_device;
 pub mod fat32;
 pub mod hexfmt;
 pub mod sdcard;
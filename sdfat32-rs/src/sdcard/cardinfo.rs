@@ -1,10 +1,29 @@
 use super::{
-    cmd::SdRegister,
+    cmd::{
+        SdAppCommand,
+        SdRegister,
+    },
+    crc::CRC7,
+    spi::SpiClock,
     SdCard,
     SdCardError,
-    BLOCK_SIZE,
 };
+use embedded_hal::{
+    digital::v2::OutputPin,
+    spi::FullDuplex,
+};
+
 
+// Multipliers (x10) for the time/rate value fields in TAAC and TRAN_SPEED
+const TIME_VALUE_X10: [u32; 16] = [0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    PartitionTable,
+    BootSectorOnly,
+    Universal,
+    Other,
+}
 
 pub struct CardId {
     manufacturer_id: u8,
@@ -50,10 +69,28 @@ impl CardId {
 
 pub struct CardSpecificData {
     version: u8,
-    tran_speed_mhz: u8,
+    taac_ns: u32,
+    nsac_clocks: u16,
+    tran_speed_kbps: u32,
     supported_command_classes: u16,
     max_read_block_len_bytes: usize,
-    capacity_mib: u32,
+    read_block_partial: bool,
+    write_block_misalign: bool,
+    read_block_misalign: bool,
+    dsr_implemented: bool,
+    sector_count: u32,
+    erase_block_enabled: bool,
+    erase_sector_size: u8,
+    wp_group_size: u8,
+    wp_group_enabled: bool,
+    r2w_factor: u8,
+    max_write_block_len_bytes: usize,
+    write_block_partial: bool,
+    file_format_group: bool,
+    copy: bool,
+    perm_write_protect: bool,
+    tmp_write_protect: bool,
+    file_format: FileFormat,
 }
 
 impl CardSpecificData {
@@ -62,9 +99,26 @@ impl CardSpecificData {
         self.version
     }
 
+    // Asynchronous part of the data access time
+    #[inline(always)]
+    pub fn taac_ns(&self) -> u32 {
+        self.taac_ns
+    }
+
+    // Clock-dependent part of the data access time
+    #[inline(always)]
+    pub fn nsac_clocks(&self) -> u16 {
+        self.nsac_clocks
+    }
+
+    #[inline(always)]
+    pub fn tran_speed_kbps(&self) -> u32 {
+        self.tran_speed_kbps
+    }
+
     #[inline(always)]
     pub fn tran_speed_mhz(&self) -> u8 {
-        self.tran_speed_mhz
+        (self.tran_speed_kbps / 1000) as u8
     }
 
     #[inline(always)]
@@ -77,15 +131,294 @@ impl CardSpecificData {
         self.max_read_block_len_bytes
     }
 
+    #[inline(always)]
+    pub fn read_block_partial(&self) -> bool {
+        self.read_block_partial
+    }
+
+    #[inline(always)]
+    pub fn write_block_misalign(&self) -> bool {
+        self.write_block_misalign
+    }
+
+    #[inline(always)]
+    pub fn read_block_misalign(&self) -> bool {
+        self.read_block_misalign
+    }
+
+    #[inline(always)]
+    pub fn dsr_implemented(&self) -> bool {
+        self.dsr_implemented
+    }
+
     #[inline(always)]
     pub fn capacity_mib(&self) -> u32 {
-        self.capacity_mib
+        // 2048 sectors per MiB
+        self.sector_count >> 11
+    }
+
+    #[inline(always)]
+    pub fn sector_count(&self) -> u32 {
+        self.sector_count
+    }
+
+    // Can the card erase single blocks, or only whole erase sectors?
+    #[inline(always)]
+    pub fn erase_block_enabled(&self) -> bool {
+        self.erase_block_enabled
+    }
+
+    // Size of an erasable sector, in write blocks
+    #[inline(always)]
+    pub fn erase_sector_size(&self) -> u8 {
+        self.erase_sector_size
+    }
+
+    // Size of a write-protect group, in erase sectors
+    #[inline(always)]
+    pub fn wp_group_size(&self) -> u8 {
+        self.wp_group_size
+    }
+
+    #[inline(always)]
+    pub fn wp_group_enabled(&self) -> bool {
+        self.wp_group_enabled
+    }
+
+    // Typical block write time as a multiple of the read access time
+    #[inline(always)]
+    pub fn r2w_factor(&self) -> u8 {
+        self.r2w_factor
+    }
+
+    #[inline(always)]
+    pub fn max_write_block_len_bytes(&self) -> usize {
+        self.max_write_block_len_bytes
+    }
+
+    #[inline(always)]
+    pub fn write_block_partial(&self) -> bool {
+        self.write_block_partial
+    }
+
+    #[inline(always)]
+    pub fn file_format_group(&self) -> bool {
+        self.file_format_group
+    }
+
+    #[inline(always)]
+    pub fn copy(&self) -> bool {
+        self.copy
+    }
+
+    #[inline(always)]
+    pub fn perm_write_protect(&self) -> bool {
+        self.perm_write_protect
+    }
+
+    #[inline(always)]
+    pub fn tmp_write_protect(&self) -> bool {
+        self.tmp_write_protect
+    }
+
+    #[inline(always)]
+    pub fn file_format(&self) -> FileFormat {
+        self.file_format
+    }
+}
+
+pub struct SdConfiguration {
+    scr_version: u8,
+    spec_version: (u8, u8),
+    data_stat_after_erase: u8,
+    security_version: u8,
+    bus_widths: u8,
+    ex_security: u8,
+    cmd_support: u8,
+}
+
+impl SdConfiguration {
+    #[inline(always)]
+    pub fn scr_version(&self) -> u8 {
+        self.scr_version
+    }
+
+    // Physical layer spec version that the card complies with, e.g. (1, 10) or (3, 0)
+    #[inline(always)]
+    pub fn spec_version(&self) -> (u8, u8) {
+        self.spec_version
+    }
+
+    // Value of the data after an erase (all 0s or all 1s)
+    #[inline(always)]
+    pub fn data_stat_after_erase(&self) -> u8 {
+        self.data_stat_after_erase
+    }
+
+    #[inline(always)]
+    pub fn security_version(&self) -> u8 {
+        self.security_version
+    }
+
+    // Bit 0 is set if the card supports a 1-bit data bus, bit 2 if it supports a 4-bit bus
+    #[inline(always)]
+    pub fn bus_widths(&self) -> u8 {
+        self.bus_widths
+    }
+
+    #[inline(always)]
+    pub fn supports_4bit_bus(&self) -> bool {
+        self.bus_widths & 0x04 > 0
+    }
+
+    #[inline(always)]
+    pub fn ex_security(&self) -> u8 {
+        self.ex_security
+    }
+
+    // Bit 0: speed class control (CMD20), bit 1: set block count (CMD23), bit 2: extension
+    // registers (CMD48/49), bit 3: extension registers multi-block (CMD58/59)
+    #[inline(always)]
+    pub fn cmd_support(&self) -> u8 {
+        self.cmd_support
+    }
+}
+
+pub struct SdStatus {
+    bus_width: u8,
+    secured_mode: bool,
+    card_type: u16,
+    protected_area_size: u32,
+    speed_class: u8,
+    performance_move_mbs: u8,
+    au_size_kib: u32,
+    erase_size_au: u16,
+    erase_timeout_s: u8,
+    erase_offset_s: u8,
+    uhs_speed_grade: u8,
+    uhs_au_size_kib: u32,
+    video_speed_class: u8,
+    app_perf_class: u8,
+    discard_support: bool,
+}
+
+impl SdStatus {
+    #[inline(always)]
+    pub fn bus_width(&self) -> u8 {
+        self.bus_width
+    }
+
+    #[inline(always)]
+    pub fn secured_mode(&self) -> bool {
+        self.secured_mode
+    }
+
+    #[inline(always)]
+    pub fn card_type(&self) -> u16 {
+        self.card_type
+    }
+
+    #[inline(always)]
+    pub fn protected_area_size(&self) -> u32 {
+        self.protected_area_size
+    }
+
+    // Speed class in MB/s (0, 2, 4, 6 or 10)
+    #[inline(always)]
+    pub fn speed_class(&self) -> u8 {
+        self.speed_class
+    }
+
+    #[inline(always)]
+    pub fn performance_move_mbs(&self) -> u8 {
+        self.performance_move_mbs
+    }
+
+    // Allocation unit size; 0 if the card doesn't say
+    #[inline(always)]
+    pub fn au_size_kib(&self) -> u32 {
+        self.au_size_kib
+    }
+
+    // Number of AUs erased at a time when computing the erase timeout
+    #[inline(always)]
+    pub fn erase_size_au(&self) -> u16 {
+        self.erase_size_au
+    }
+
+    #[inline(always)]
+    pub fn erase_timeout_s(&self) -> u8 {
+        self.erase_timeout_s
+    }
+
+    #[inline(always)]
+    pub fn erase_offset_s(&self) -> u8 {
+        self.erase_offset_s
+    }
+
+    // UHS speed grade in MB/s (0, 10 or 30)
+    #[inline(always)]
+    pub fn uhs_speed_grade(&self) -> u8 {
+        self.uhs_speed_grade
+    }
+
+    #[inline(always)]
+    pub fn uhs_au_size_kib(&self) -> u32 {
+        self.uhs_au_size_kib
+    }
+
+    // Video speed class in MB/s (0, 6, 10, 30, 60 or 90)
+    #[inline(always)]
+    pub fn video_speed_class(&self) -> u8 {
+        self.video_speed_class
+    }
+
+    // Application performance class: 0 for none, 1 for A1, 2 for A2
+    #[inline(always)]
+    pub fn app_perf_class(&self) -> u8 {
+        self.app_perf_class
+    }
+
+    #[inline(always)]
+    pub fn discard_support(&self) -> bool {
+        self.discard_support
+    }
+}
+
+fn au_size_kib(code: u32) -> u32 {
+    match code {
+        0 => 0,
+        1..=9 => 16 << (code - 1),
+        10 => 8 * 1024,
+        11 => 12 * 1024,
+        12 => 16 * 1024,
+        13 => 24 * 1024,
+        14 => 32 * 1024,
+        _ => 64 * 1024,
+    }
+}
+
+// Pull bits [msb:lsb] out of a big-endian register, using the bit numbering from the SD spec
+// (i.e., bit 0 is the least significant bit of the last byte)
+pub(crate) fn register_bits(data: &[u8], msb: u16, lsb: u16) -> u32 {
+    let mut val: u32 = 0;
+    for bit in (lsb..=msb).rev() {
+        let byte = data[data.len() - 1 - (bit >> 3) as usize];
+        val = (val << 1) | ((byte >> (bit & 0x07)) & 0x01) as u32;
     }
+    val
 }
 
-impl<CSPIN: avr_hal_generic::port::PinOps> SdCard<CSPIN> {
+impl<SPI, CS> SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8> + SpiClock,
+    CS: OutputPin,
+{
     pub fn read_card_id(&mut self) -> Result<CardId, SdCardError> {
         let data = self.read_register(SdRegister::CID)?;
+        if CRC7(&data[0..15]) != data[15] {
+            return Err(SdCardError::RegisterCRCError);
+        }
         Ok(CardId {
             manufacturer_id: data[0],
             oem_id: (data[1], data[2]),
@@ -102,25 +435,129 @@ impl<CSPIN: avr_hal_generic::port::PinOps> SdCard<CSPIN> {
 
     pub fn read_card_specific_data(&mut self) -> Result<CardSpecificData, SdCardError> {
         let data = self.read_register(SdRegister::CSD)?;
-        let version = (data[0] >> 6) + 1;
-        if version != 2 {
-            return Err(SdCardError::SDVersionOneUnsupported);
+        if CRC7(&data[0..15]) != data[15] {
+            return Err(SdCardError::RegisterCRCError);
         }
+
+        let version = register_bits(&data, 127, 126) as u8 + 1;
+        let read_bl_len = register_bits(&data, 83, 80) as u8;
+
+        // Blocks can only be 512, 1024 or 2048 bytes; anything else means the CSD is garbage
+        if !(9..=11).contains(&read_bl_len) {
+            return Err(SdCardError::RegisterError);
+        }
+        let sector_count = match version {
+            1 => {
+                // Capacity is (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) * 2^READ_BL_LEN bytes
+                let c_size = register_bits(&data, 73, 62);
+                let c_size_mult = register_bits(&data, 49, 47) as u8;
+                (c_size + 1) << (c_size_mult + 2 + read_bl_len - 9)
+            },
+            // C_SIZE counts units of 512 KiB, i.e., 1024 sectors
+            2 => (register_bits(&data, 69, 48) + 1) << 10,
+            _ => return Err(SdCardError::RegisterError),
+        };
+
+        // TAAC and TRAN_SPEED are both a 4-bit value and a 3-bit power-of-ten unit; TAAC's unit
+        // is 1ns and TRAN_SPEED's unit is 100kbit/s
+        let taac = data[1];
+        let tran_speed = data[3];
         Ok(CardSpecificData {
             version,
-            tran_speed_mhz: match data[3] {
-                0x32 => 25,
-                0x5a => 50,
+            taac_ns: TIME_VALUE_X10[((taac >> 3) & 0x0f) as usize] * 10u32.pow((taac & 0x07) as u32) / 10,
+            nsac_clocks: data[2] as u16 * 100,
+            tran_speed_kbps: TIME_VALUE_X10[((tran_speed >> 3) & 0x0f) as usize]
+                * 10u32.pow((tran_speed & 0x07) as u32 + 2)
+                / 10,
+            supported_command_classes: register_bits(&data, 95, 84) as u16,
+            max_read_block_len_bytes: 1 << read_bl_len,
+            read_block_partial: register_bits(&data, 79, 79) > 0,
+            write_block_misalign: register_bits(&data, 78, 78) > 0,
+            read_block_misalign: register_bits(&data, 77, 77) > 0,
+            dsr_implemented: register_bits(&data, 76, 76) > 0,
+            sector_count,
+            erase_block_enabled: register_bits(&data, 46, 46) > 0,
+            erase_sector_size: register_bits(&data, 45, 39) as u8 + 1,
+            wp_group_size: register_bits(&data, 38, 32) as u8 + 1,
+            wp_group_enabled: register_bits(&data, 31, 31) > 0,
+            r2w_factor: 1 << register_bits(&data, 28, 26),
+            max_write_block_len_bytes: 1 << register_bits(&data, 25, 22),
+            write_block_partial: register_bits(&data, 21, 21) > 0,
+            file_format_group: register_bits(&data, 15, 15) > 0,
+            copy: register_bits(&data, 14, 14) > 0,
+            perm_write_protect: register_bits(&data, 13, 13) > 0,
+            tmp_write_protect: register_bits(&data, 12, 12) > 0,
+            file_format: match register_bits(&data, 11, 10) {
+                0 => FileFormat::PartitionTable,
+                1 => FileFormat::BootSectorOnly,
+                2 => FileFormat::Universal,
+                _ => FileFormat::Other,
+            },
+        })
+    }
+
+    pub fn read_scr(&mut self) -> Result<SdConfiguration, SdCardError> {
+        let mut data = [0u8; 8];
+        self.read_app_register(SdAppCommand::SendScr, &mut data)?;
+
+        // The spec version is spread across several fields that were added in later versions
+        let spec_version = match (
+            register_bits(&data, 59, 56),
+            register_bits(&data, 47, 47),
+            register_bits(&data, 42, 42),
+            register_bits(&data, 41, 38),
+        ) {
+            (0, ..) => (1, 0),
+            (1, ..) => (1, 10),
+            (2, 0, ..) => (2, 0),
+            (2, 1, 0, 0) => (3, 0),
+            (2, 1, 1, 0) => (4, 0),
+            (2, 1, _, x) => (x as u8 + 4, 0),
+            _ => (0, 0),
+        };
+        Ok(SdConfiguration {
+            scr_version: register_bits(&data, 63, 60) as u8,
+            spec_version,
+            data_stat_after_erase: register_bits(&data, 55, 55) as u8,
+            security_version: register_bits(&data, 54, 52) as u8,
+            bus_widths: register_bits(&data, 51, 48) as u8,
+            ex_security: register_bits(&data, 46, 43) as u8,
+            cmd_support: register_bits(&data, 35, 32) as u8,
+        })
+    }
+
+    pub fn read_sd_status(&mut self) -> Result<SdStatus, SdCardError> {
+        let mut data = [0u8; 64];
+        self.read_app_register(SdAppCommand::SdStatus, &mut data)?;
+        Ok(SdStatus {
+            bus_width: match register_bits(&data, 511, 510) {
+                2 => 4,
+                _ => 1,
+            },
+            secured_mode: register_bits(&data, 509, 509) > 0,
+            card_type: register_bits(&data, 495, 480) as u16,
+            protected_area_size: register_bits(&data, 479, 448),
+            speed_class: match register_bits(&data, 447, 440) {
+                1 => 2,
+                2 => 4,
+                3 => 6,
+                4 => 10,
                 _ => 0,
             },
-            supported_command_classes: ((data[4] as u16) << 4) | ((data[5] as u16) >> 4),
-            max_read_block_len_bytes: match data[5] & 0x0f {
-                0x09 => BLOCK_SIZE,
+            performance_move_mbs: register_bits(&data, 439, 432) as u8,
+            au_size_kib: au_size_kib(register_bits(&data, 431, 428)),
+            erase_size_au: register_bits(&data, 423, 408) as u16,
+            erase_timeout_s: register_bits(&data, 407, 402) as u8,
+            erase_offset_s: register_bits(&data, 401, 400) as u8,
+            uhs_speed_grade: match register_bits(&data, 399, 396) {
+                1 => 10,
+                3 => 30,
                 _ => 0,
             },
-            capacity_mib: ((((((data[7] & 0x3f) as u32) << 16 | (data[8] as u32) << 8 | (data[9] as u32)) + 1) as u64
-                * 512000)
-                >> 20) as u32,
+            uhs_au_size_kib: au_size_kib(register_bits(&data, 395, 392)),
+            video_speed_class: register_bits(&data, 391, 384) as u8,
+            app_perf_class: register_bits(&data, 339, 336) as u8,
+            discard_support: register_bits(&data, 313, 313) > 0,
         })
     }
 }
@@ -1,24 +1,44 @@
 use super::{
     constants::*,
-    crc::CRC7,
+    crc::{
+        CRC16,
+        CRC7,
+    },
+    spi::SpiClock,
+    status::r1_to_result,
     SdCard,
     SdCardError,
 };
+use embedded_hal::{
+    digital::v2::OutputPin,
+    spi::FullDuplex,
+};
 
 
 #[derive(Clone, Copy)]
 pub(crate) enum SdCommand {
     GoIdleState = 0,
+    SendOpCondition = 1,
     ReadStop = 12,
+    SendStatus = 13,
+    SetBlockLen = 16,
     ReadBlock = 17,
     ReadMultipleBlocks = 18,
+    WriteBlock = 24,
+    WriteMultipleBlocks = 25,
+    EraseStart = 32,
+    EraseEnd = 33,
+    Erase = 38,
     AppCommand = 55,
     SetCRC = 59,
 }
 
 #[derive(Clone, Copy)]
 pub(crate) enum SdAppCommand {
+    SdStatus = 13,
+    SetWriteBlockEraseCount = 23,
     SendOpCondition = 41,
+    SendScr = 51,
 }
 
 #[derive(Clone, Copy)]
@@ -34,43 +54,113 @@ pub(crate) enum SdRegister {
 }
 
 
-impl<CSPIN: avr_hal_generic::port::PinOps> SdCard<CSPIN> {
+impl<SPI, CS> SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8> + SpiClock,
+    CS: OutputPin,
+{
     pub(crate) fn read_data(&mut self, dest: &mut [u8]) -> Result<(), SdCardError> {
         let start_time_ms = (self.millis)();
 
-        let mut res = self.transfer(0xff);
+        let mut res = self.transfer(0xff)?;
         while res == 0xff {
             if (self.millis)() >= start_time_ms + SD_READ_TIMEOUT_MS {
                 return Err(SdCardError::Timeout);
             }
-            res = self.transfer(0xff);
+            res = self.transfer(0xff)?;
         }
 
         if res != DATA_START_SECTOR {
             return Err(SdCardError::ReadError);
         }
 
-        for i in 0..dest.len() {
-            dest[i] = self.transfer(0xff);
+        for byte in dest.iter_mut() {
+            *byte = self.transfer(0xff)?;
         }
 
-        let _crc: u16 = ((self.transfer(0xff) as u16) << 8) | (self.transfer(0xff) as u16);
+        let crc: u16 = ((self.transfer(0xff)? as u16) << 8) | (self.transfer(0xff)? as u16);
+        if crc != CRC16(dest) {
+            return Err(SdCardError::ReadCRCError);
+        }
 
         Ok(())
     }
 
-    pub(crate) fn read_register(&mut self, reg: SdRegister) -> Result<[u8; 16], SdCardError> {
-        self.select();
-        match self.send_card_command_helper(reg as u8, 0) {
-            Ok(b) if b == 0 => (),
-            Ok(_) => return Err(SdCardError::RegisterError),
-            Err(e) => return Err(e),
+    pub(crate) fn write_data(&mut self, token: u8, src: &[u8]) -> Result<(), SdCardError> {
+        self.transfer(token)?;
+        for byte in src.iter() {
+            self.transfer(*byte)?;
+        }
+
+        // The card checks the CRC since we turned on CRC checking during init
+        let crc = CRC16(src);
+        self.transfer((crc >> 8) as u8)?;
+        self.transfer(crc as u8)?;
+
+        // The card replies with a data response token of the form xxx0sss1
+        match self.transfer(0xff)? & DATA_RES_MASK {
+            DATA_RES_ACCEPTED => (),
+            DATA_RES_CRC_ERROR => return Err(SdCardError::WriteCRCError),
+            DATA_RES_WRITE_ERROR => return Err(SdCardError::WriteError),
+            _ => return Err(SdCardError::Unknown),
+        }
+
+        // The card holds MISO low while it's programming the data
+        self.wait_not_busy(SD_WRITE_TIMEOUT_MS)
+    }
+
+    pub(crate) fn wait_not_busy(&mut self, timeout_ms: u32) -> Result<(), SdCardError> {
+        let start_time_ms = (self.millis)();
+        while self.transfer(0xff)? != 0xff {
+            if (self.millis)() >= start_time_ms + timeout_ms {
+                return Err(SdCardError::Timeout);
+            }
         }
+        Ok(())
+    }
 
+    pub(crate) fn read_register(&mut self, reg: SdRegister) -> Result<[u8; 16], SdCardError> {
         let mut data = [0u8; 16];
-        self.read_data(&mut data)?;
-        self.unselect();
-        Ok(data)
+        let mut retries = 0;
+        loop {
+            self.select()?;
+            let res = match self.send_card_command_helper(reg as u8, 0) {
+                Ok(0) => self.read_data(&mut data),
+                Ok(_) => Err(SdCardError::RegisterError),
+                Err(e) => Err(e),
+            };
+            self.unselect();
+
+            match res {
+                Ok(()) => return Ok(data),
+                Err(SdCardError::ReadCRCError) if retries < self.crc_retry_count => retries += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Application-specific registers (SCR, SD Status) are read like data blocks, just like the
+    // CID and CSD registers, but they have different lengths
+    pub(crate) fn read_app_register(&mut self, cmd: SdAppCommand, dest: &mut [u8]) -> Result<(), SdCardError> {
+        let mut retries = 0;
+        loop {
+            self.select()?;
+            let res = match self.send_card_app_command(cmd, 0) {
+                Ok(0) => match cmd {
+                    // ACMD13 responds with R2, which has an extra status byte after R1
+                    SdAppCommand::SdStatus => self.transfer(0xff).and_then(|_| self.read_data(dest)),
+                    _ => self.read_data(dest),
+                },
+                Ok(_) => Err(SdCardError::RegisterError),
+                Err(e) => Err(e),
+            };
+            self.unselect();
+
+            match res {
+                Err(SdCardError::ReadCRCError) if retries < self.crc_retry_count => retries += 1,
+                _ => return res,
+            }
+        }
     }
 
     pub(crate) fn send_card_app_command(&mut self, cmd: SdAppCommand, arg: u32) -> Result<u8, SdCardError> {
@@ -80,47 +170,50 @@ impl<CSPIN: avr_hal_generic::port::PinOps> SdCard<CSPIN> {
     }
 
     pub(crate) fn send_card_command(&mut self, cmd: SdCommand, arg: u32) -> Result<(), SdCardError> {
-        match self.send_card_command_helper(cmd as u8, arg) {
-            Ok(b) if b == 0x0 || b == 0x1 => Ok(()),
-            Ok(b) if b & 0x02 != 0 => Err(SdCardError::EraseReset),
-            Ok(b) if b & 0x04 != 0 => Err(SdCardError::IllegalCommand),
-            Ok(b) if b & 0x08 != 0 => Err(SdCardError::CRCError),
-            Ok(b) if b & 0x08 != 0 => Err(SdCardError::EraseSequenceError),
-            Ok(b) if b & 0x10 != 0 => Err(SdCardError::AddressError),
-            Ok(b) if b & 0x20 != 0 => Err(SdCardError::ParameterError),
-            Ok(_) => Err(SdCardError::Unknown),
-            Err(e) => Err(e),
-        }
+        r1_to_result(self.send_card_command_helper(cmd as u8, arg)?)
+    }
+
+    // Returns the raw R1 response, for callers that care about the idle bit
+    pub(crate) fn send_card_command_r1(&mut self, cmd: SdCommand, arg: u32) -> Result<u8, SdCardError> {
+        self.send_card_command_helper(cmd as u8, arg)
     }
 
     pub(crate) fn send_card_command_wide(&mut self, cmd: SdCommandWide, arg: u32) -> Result<[u8; 4], SdCardError> {
         let mut response = [0, 0, 0, 0];
-        self.send_card_command_helper(cmd as u8, arg)?;
-        for i in 0..4 {
-            response[i] = self.transfer(0xff);
+
+        // Cards that don't understand the command only send back R1, without the extra 4 bytes
+        if self.send_card_command_helper(cmd as u8, arg)? & 0x04 != 0 {
+            return Err(SdCardError::IllegalCommand);
+        }
+        for byte in response.iter_mut() {
+            *byte = self.transfer(0xff)?;
         }
         Ok(response)
     }
 
     fn send_card_command_helper(&mut self, cmd: u8, arg: u32) -> Result<u8, SdCardError> {
-        // Wait for card to be ready
-        while cmd != (SdCommand::GoIdleState as u8) && self.transfer(0xff) != 0xff {}
+        // Wait for card to be ready; the card is still streaming data when we want to stop a
+        // multi-block read, so don't wait in that case.  A card that never comes out of busy is
+        // given the same time as a write would get before giving up on it.
+        if cmd != (SdCommand::GoIdleState as u8) && cmd != (SdCommand::ReadStop as u8) {
+            self.wait_not_busy(SD_WRITE_TIMEOUT_MS)?;
+        }
 
         // Command format is 01CCCCCCAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAARRRRRRR1
         // where C is the 6-bit command, A is the 32-bit argument, and R is the 7-bit CRC
-        let data = [0x40 | cmd as u8, (arg >> 24) as u8, (arg >> 16) as u8, (arg >> 8) as u8, arg as u8];
-        let crc = CRC7(data);
+        let data = [0x40 | cmd, (arg >> 24) as u8, (arg >> 16) as u8, (arg >> 8) as u8, arg as u8];
+        let crc = CRC7(&data);
         for byte in data.iter() {
-            self.transfer(*byte);
+            self.transfer(*byte)?;
         }
-        self.transfer(crc);
+        self.transfer(crc)?;
 
         // SD card needs at least 8 clock cycles to respond to a command
-        self.transfer(0xff);
+        self.transfer(0xff)?;
 
         // Poll for a response
         for _ in 0..10 {
-            let response = self.transfer(0xff);
+            let response = self.transfer(0xff)?;
 
             // If the last bit is 1 the card hasn't responded yet
             if response & 0x80 == 0 {
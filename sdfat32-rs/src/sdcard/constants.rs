@@ -1,5 +1,15 @@
 pub const BLOCK_SIZE: usize = 512;
 pub(crate) const DATA_START_SECTOR: u8 = 0xfe;
+pub(crate) const WRITE_MULTIPLE_TOKEN: u8 = 0xfc;
+pub(crate) const STOP_TRAN_TOKEN: u8 = 0xfd;
+pub(crate) const DATA_RES_MASK: u8 = 0x1f;
+pub(crate) const DATA_RES_ACCEPTED: u8 = 0x05;
+pub(crate) const DATA_RES_CRC_ERROR: u8 = 0x0b;
+pub(crate) const DATA_RES_WRITE_ERROR: u8 = 0x0d;
 pub(crate) const SD_CMD0_RETRY_COUNT: u8 = 10;
+pub(crate) const SD_CRC_RETRY_COUNT: u8 = 3;
+pub(crate) const SD_DEFAULT_AU_SECTORS: u32 = 8192;
+pub(crate) const SD_ERASE_TIMEOUT_PER_AU_MS: u32 = 250;
 pub(crate) const SD_INIT_TIMEOUT_MS: u32 = 2000;
 pub(crate) const SD_READ_TIMEOUT_MS: u32 = 300;
+pub(crate) const SD_WRITE_TIMEOUT_MS: u32 = 600;
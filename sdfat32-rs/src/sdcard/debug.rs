@@ -1,11 +1,16 @@
-use crate::sdcard::cardinfo::{
-    CardId,
-    CardSpecificData,
-};
-use avr_progmem_str::{
-    pm_write,
-    progmem_str,
+use crate::sdcard::{
+    cardinfo::{
+        CardId,
+        CardSpecificData,
+        FileFormat,
+        SdConfiguration,
+        SdStatus,
+    },
+    status::CardStatus,
 };
+use avr_progmem_str::pm_write;
+#[cfg(target_arch = "avr")]
+use avr_progmem_str::progmem_str;
 use ufmt::{
     uDebug,
     uWrite,
@@ -63,14 +68,118 @@ impl uDebug for CardSpecificData {
         W: uWrite + ?Sized,
     {
         pm_write!(out, "  CSD version:               {}\n", self.version())?;
-        pm_write!(out, "  Max data transfer rate:    {} MHz\n", self.tran_speed_mhz())?;
+        pm_write!(out, "  Data access time:          {} ns + {} clocks\n", self.taac_ns(), self.nsac_clocks())?;
+        pm_write!(out, "  Max data transfer rate:    {} kbit/s\n", self.tran_speed_kbps())?;
         pm_write!(out, "  Supported command classes: ")?;
         for i in 0..12 {
             out.write_char((((self.supported_command_classes() >> (11 - i)) & 0x01) as u8 + b'0') as char)?;
         }
         out.write_char('\n')?;
         pm_write!(out, "  Max data read block size:  {}\n", self.max_read_block_len_bytes(),)?;
-        pm_write!(out, "  Card capacity:             {} MiB\n", self.capacity_mib())?;
+        pm_write!(out, "  Max data write block size: {}\n", self.max_write_block_len_bytes(),)?;
+        pm_write!(
+            out,
+            "  Partial/misaligned blocks: read {}/{}",
+            self.read_block_partial(),
+            self.read_block_misalign()
+        )?;
+        pm_write!(out, "; write {}/{}\n", self.write_block_partial(), self.write_block_misalign())?;
+        pm_write!(out, "  DSR implemented:           {}\n", self.dsr_implemented())?;
+        pm_write!(out, "  Card capacity:             {} sectors ({} MiB)\n", self.sector_count(), self.capacity_mib())?;
+        pm_write!(out, "  Single block erase:        {}\n", self.erase_block_enabled())?;
+        pm_write!(out, "  Erase sector size:         {} blocks\n", self.erase_sector_size())?;
+        pm_write!(out, "  Write protect group size:  {} sectors", self.wp_group_size())?;
+        pm_write!(out, " (enabled = {})\n", self.wp_group_enabled())?;
+        pm_write!(out, "  Read-to-write factor:      {}\n", self.r2w_factor())?;
+        pm_write!(out, "  Copy flag:                 {}\n", self.copy())?;
+        pm_write!(out, "  Write protect:             permanent = {}", self.perm_write_protect())?;
+        pm_write!(out, ", temporary = {}\n", self.tmp_write_protect())?;
+        pm_write!(out, "  File format:               ")?;
+        if self.file_format_group() {
+            pm_write!(out, "Reserved\n")?;
+        } else {
+            match self.file_format() {
+                FileFormat::PartitionTable => pm_write!(out, "Partition table\n")?,
+                FileFormat::BootSectorOnly => pm_write!(out, "Boot sector only\n")?,
+                FileFormat::Universal => pm_write!(out, "Universal\n")?,
+                FileFormat::Other => pm_write!(out, "Other\n")?,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl uDebug for SdConfiguration {
+    fn fmt<W>(&self, out: &mut Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        pm_write!(out, "  SCR version:               {}\n", self.scr_version())?;
+        pm_write!(out, "  SD spec version:           {}.{}\n", self.spec_version().0, self.spec_version().1)?;
+        pm_write!(out, "  Data after erase:          {}\n", self.data_stat_after_erase())?;
+        pm_write!(out, "  Security version:          {}", self.security_version())?;
+        pm_write!(out, " (extended = {})\n", self.ex_security())?;
+        pm_write!(out, "  Bus widths:                1-bit")?;
+        if self.supports_4bit_bus() {
+            pm_write!(out, ", 4-bit")?;
+        }
+        out.write_char('\n')?;
+        pm_write!(out, "  Supported commands:        ")?;
+        for i in 0..4 {
+            out.write_char((((self.cmd_support() >> (3 - i)) & 0x01) + b'0') as char)?;
+        }
+        out.write_char('\n')?;
+        Ok(())
+    }
+}
+
+impl uDebug for SdStatus {
+    fn fmt<W>(&self, out: &mut Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        pm_write!(out, "  Bus width:                 {}-bit\n", self.bus_width())?;
+        pm_write!(out, "  Secured mode:              {}\n", self.secured_mode())?;
+        pm_write!(out, "  Card type:                 {}\n", self.card_type())?;
+        pm_write!(out, "  Protected area size:       {}\n", self.protected_area_size())?;
+        pm_write!(out, "  Speed class:               {} MB/s\n", self.speed_class())?;
+        pm_write!(out, "  UHS speed grade:           {} MB/s\n", self.uhs_speed_grade())?;
+        pm_write!(out, "  Video speed class:         {} MB/s\n", self.video_speed_class())?;
+        pm_write!(out, "  Move performance:          {} MB/s\n", self.performance_move_mbs())?;
+        pm_write!(out, "  App performance class:     ")?;
+        match self.app_perf_class() {
+            0 => pm_write!(out, "None\n")?,
+            c => pm_write!(out, "A{}\n", c)?,
+        };
+        pm_write!(out, "  AU size:                   {} KiB", self.au_size_kib())?;
+        pm_write!(out, " (UHS {} KiB)\n", self.uhs_au_size_kib())?;
+        pm_write!(out, "  Erase size:                {} AUs\n", self.erase_size_au())?;
+        pm_write!(out, "  Erase timeout/offset:      {}s/{}s\n", self.erase_timeout_s(), self.erase_offset_s())?;
+        pm_write!(out, "  Discard support:           {}\n", self.discard_support())?;
+        Ok(())
+    }
+}
+
+impl uDebug for CardStatus {
+    fn fmt<W>(&self, out: &mut Formatter<W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        pm_write!(out, "  Idle:                      {}\n", self.idle())?;
+        pm_write!(out, "  Card locked:               {}\n", self.card_locked())?;
+        pm_write!(out, "  Erase reset:               {}\n", self.erase_reset())?;
+        pm_write!(out, "  Erase sequence error:      {}\n", self.erase_sequence_error())?;
+        pm_write!(out, "  Erase param error:         {}\n", self.erase_param())?;
+        pm_write!(out, "  WP erase skip:             {}\n", self.wp_erase_skip())?;
+        pm_write!(out, "  WP violation:              {}\n", self.wp_violation())?;
+        pm_write!(out, "  Illegal command:           {}\n", self.illegal_command())?;
+        pm_write!(out, "  CRC error:                 {}\n", self.crc_error())?;
+        pm_write!(out, "  Address error:             {}\n", self.address_error())?;
+        pm_write!(out, "  Parameter error:           {}\n", self.parameter_error())?;
+        pm_write!(out, "  Out of range:              {}\n", self.out_of_range())?;
+        pm_write!(out, "  ECC failed:                {}\n", self.ecc_failed())?;
+        pm_write!(out, "  Card controller error:     {}\n", self.cc_error())?;
+        pm_write!(out, "  General error:             {}\n", self.error())?;
         Ok(())
     }
 }
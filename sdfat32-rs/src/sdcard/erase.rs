@@ -0,0 +1,121 @@
+use super::{
+    cmd::SdCommand,
+    constants::*,
+    spi::SpiClock,
+    SdCard,
+    SdCardError,
+};
+use embedded_hal::{
+    digital::v2::OutputPin,
+    spi::FullDuplex,
+};
+
+
+// Everything needed to check an erase range and work out how long it should take; read from the
+// CSD and SD Status registers the first time we erase something
+#[derive(Clone, Copy)]
+pub(crate) struct EraseInfo {
+    // Standard capacity cards that don't set ERASE_BLK_EN can only erase whole erase sectors
+    group_sectors: u32,
+    au_sectors: u32,
+    erase_size_au: u16,
+    erase_timeout_s: u8,
+    erase_offset_s: u8,
+}
+
+impl<SPI, CS> SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8> + SpiClock,
+    CS: OutputPin,
+{
+    // Erase sectors start_sector..=end_sector; afterwards they read back as all 0s or all 1s,
+    // depending on the card (see SdConfiguration::data_stat_after_erase)
+    pub fn erase(&mut self, start_sector: u32, end_sector: u32) -> Result<(), SdCardError> {
+        if end_sector < start_sector {
+            return Err(SdCardError::ParameterError);
+        }
+
+        let info = self.erase_info()?;
+        // No card has a sector at u32::MAX, so a range that ends there can't be right
+        let after_end_sector = match end_sector.checked_add(1) {
+            Some(after_end_sector) => after_end_sector,
+            None => return Err(SdCardError::AddressError),
+        };
+        if !start_sector.is_multiple_of(info.group_sectors) || !after_end_sector.is_multiple_of(info.group_sectors) {
+            return Err(SdCardError::AddressError);
+        }
+        let timeout_ms = info.timeout_ms(after_end_sector - start_sector);
+
+        self.select()?;
+        let res = self.erase_helper(start_sector, end_sector, timeout_ms);
+        self.unselect();
+        res
+    }
+
+    // Erase every sector on the card, including the partition table
+    pub fn wipe(&mut self) -> Result<(), SdCardError> {
+        let sector_count = self.read_card_specific_data()?.sector_count();
+        if sector_count == 0 {
+            return Ok(());
+        }
+        self.erase(0, sector_count - 1)
+    }
+
+    fn erase_helper(&mut self, start_sector: u32, end_sector: u32, timeout_ms: u32) -> Result<(), SdCardError> {
+        self.send_card_command(SdCommand::EraseStart, self.sector_address(start_sector))?;
+        self.send_card_command(SdCommand::EraseEnd, self.sector_address(end_sector))?;
+        self.send_card_command(SdCommand::Erase, 0)?;
+
+        // The card holds MISO low until the erase is finished
+        let res = self.wait_not_busy(timeout_ms);
+        self.check_status(res)
+    }
+
+    // Size of the card's allocation unit in sectors, which is what it's best to line up writes
+    // and filesystem structures on
+    pub(crate) fn au_sectors(&mut self) -> Result<u32, SdCardError> {
+        Ok(self.erase_info()?.au_sectors)
+    }
+
+    fn erase_info(&mut self) -> Result<EraseInfo, SdCardError> {
+        if let Some(info) = self.erase_info {
+            return Ok(info);
+        }
+
+        let csd = self.read_card_specific_data()?;
+        let group_sectors = if csd.erase_block_enabled() { 1 } else { csd.erase_sector_size() as u32 };
+
+        // MMC cards don't have an SD Status register, so fall back to a conservative guess
+        let info = match self.read_sd_status() {
+            Ok(status) if status.au_size_kib() > 0 && status.erase_size_au() > 0 => EraseInfo {
+                group_sectors,
+                au_sectors: status.au_size_kib() * 2,
+                erase_size_au: status.erase_size_au(),
+                erase_timeout_s: status.erase_timeout_s(),
+                erase_offset_s: status.erase_offset_s(),
+            },
+            _ => EraseInfo {
+                group_sectors,
+                au_sectors: SD_DEFAULT_AU_SECTORS,
+                erase_size_au: 0,
+                erase_timeout_s: 0,
+                erase_offset_s: 0,
+            },
+        };
+        self.erase_info = Some(info);
+        Ok(info)
+    }
+}
+
+impl EraseInfo {
+    fn timeout_ms(&self, sector_count: u32) -> u32 {
+        let au_count = sector_count.div_ceil(self.au_sectors);
+        if self.erase_size_au == 0 || self.erase_timeout_s == 0 {
+            return SD_ERASE_TIMEOUT_PER_AU_MS.saturating_mul(au_count).max(SD_WRITE_TIMEOUT_MS);
+        }
+
+        // From the SD spec: erase_timeout / erase_size * au_count + erase_offset
+        let timeout_ms = (self.erase_timeout_s as u32 * 1000).saturating_mul(au_count) / self.erase_size_au as u32;
+        timeout_ms.saturating_add(self.erase_offset_s as u32 * 1000).max(SD_WRITE_TIMEOUT_MS)
+    }
+}
@@ -5,13 +5,22 @@ use super::{
         SdCommandWide,
     },
     constants::*,
+    spi::SpiClock,
     SdCard,
     SdCardError,
     SdVersion,
 };
+use embedded_hal::{
+    digital::v2::OutputPin,
+    spi::FullDuplex,
+};
 
 
-impl<CSPIN: avr_hal_generic::port::PinOps> SdCard<CSPIN> {
+impl<SPI, CS> SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8> + SpiClock,
+    CS: OutputPin,
+{
     pub(crate) fn init_spi(&mut self) -> Result<(), SdCardError> {
         let (mut i, mut init) = (0, false);
         while !init {
@@ -32,10 +41,21 @@ impl<CSPIN: avr_hal_generic::port::PinOps> SdCard<CSPIN> {
             SdVersion::One => 0,
         };
 
-        let (start_time_ms, mut init_done) = ((self.millis)(), false);
+        // MMC cards don't know about ACMD41 (or CMD55), so if the card rejects it we fall back to
+        // the MMC initialization command (CMD1)
+        let (start_time_ms, mut init_done, mut is_mmc) = ((self.millis)(), false, false);
         while (self.millis)() <= start_time_ms + SD_INIT_TIMEOUT_MS && !init_done {
-            match self.send_card_app_command(SdAppCommand::SendOpCondition, acmd41_arg) {
-                Ok(b) if b == 0x0 => init_done = true,
+            let res = if is_mmc {
+                self.send_card_command_r1(SdCommand::SendOpCondition, 0)
+            } else {
+                self.send_card_app_command(SdAppCommand::SendOpCondition, acmd41_arg)
+            };
+            match res {
+                Ok(0) => init_done = true,
+                Ok(b) if b & 0x04 != 0 && !is_mmc => match self.version {
+                    SdVersion::One => is_mmc = true,
+                    SdVersion::Two { sdhc: _ } => return Err(SdCardError::IllegalCommand),
+                },
                 Ok(_) => continue,
                 Err(e) => return Err(e),
             }
@@ -45,6 +65,11 @@ impl<CSPIN: avr_hal_generic::port::PinOps> SdCard<CSPIN> {
             return Err(SdCardError::Timeout);
         }
 
+        // Version 1 cards are always standard capacity, and don't report it in the OCR
+        if let SdVersion::One = self.version {
+            return Ok(());
+        }
+
         // Check if card supports SDHC
         match self.send_card_command_wide(SdCommandWide::ReadOCR, 0) {
             Ok(data) => {
@@ -70,15 +95,21 @@ impl<CSPIN: avr_hal_generic::port::PinOps> SdCard<CSPIN> {
                     return Err(SdCardError::CardCheckPatternMismatch);
                 }
             },
-            Err(SdCardError::IllegalCommand) => {
-                self.version = SdVersion::One;
-                return Err(SdCardError::SDVersionOneUnsupported);
-            },
+            Err(SdCardError::IllegalCommand) => self.version = SdVersion::One,
             Err(e) => return Err(e),
         }
         Ok(())
     }
 
+    pub(crate) fn set_block_len(&mut self) -> Result<(), SdCardError> {
+        // SDHC/SDXC cards always use 512-byte blocks, but standard capacity cards might default
+        // to something larger
+        if let SdVersion::Two { sdhc: true } = self.version {
+            return Ok(());
+        }
+        self.send_card_command(SdCommand::SetBlockLen, BLOCK_SIZE as u32)
+    }
+
     pub(crate) fn enable_crc(&mut self) -> Result<(), SdCardError> {
         // By default in SPI mode only CMD0 and CMD8 are CRC-checked;
         // this command will enable it for all commands
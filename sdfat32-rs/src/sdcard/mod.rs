@@ -3,21 +3,35 @@ mod cmd;
 mod constants;
 mod crc;
 mod debug;
+mod erase;
 mod init;
 mod rwdata;
+#[allow(clippy::module_inception)]
 mod sdcard;
+#[cfg(feature = "sim")]
+pub mod sim;
+mod spi;
+mod status;
 
+pub use cardinfo::{
+    CardId,
+    CardSpecificData,
+    FileFormat,
+    SdConfiguration,
+    SdStatus,
+};
 pub use constants::BLOCK_SIZE;
-pub(crate) use rwdata::{
-    Block,
-    DATA_BUFFER,
-    FS_BUFFER,
+pub use rwdata::{
+    ReadStream,
+    WriteStream,
 };
 pub use sdcard::{
     SdCard,
     SdCardRef,
     SdVersion,
 };
+pub use spi::SpiClock;
+pub use status::CardStatus;
 
 pub enum SdCardError {
     NoResponse = 1,
@@ -28,10 +42,22 @@ pub enum SdCardError {
     AddressError,
     ParameterError,
     RegisterError,
+    RegisterCRCError,
     ReadError,
-    SDVersionOneUnsupported,
+    ReadCRCError,
+    WriteError,
+    WriteCRCError,
     CardCheckPatternMismatch,
-    DataBufferLocked,
     Timeout,
+    SpiError,
+    ChipSelectError,
+    CardLocked,
+    WriteProtectViolation,
+    WriteProtectEraseSkip,
+    EraseParamError,
+    OutOfRange,
+    ECCFailed,
+    CardControllerError,
+    CardError,
     Unknown,
 }
@@ -1,88 +1,230 @@
 use super::{
-    cmd::SdCommand,
+    cmd::{
+        SdAppCommand,
+        SdCommand,
+    },
     constants::*,
+    spi::SpiClock,
     SdCard,
     SdCardError,
 };
-use avr_hal_generic::port::PinOps;
-use core::marker::PhantomData;
+use crate::block_device::BlockDevice;
+use embedded_hal::{
+    digital::v2::OutputPin,
+    spi::FullDuplex,
+};
+
+
+// A multi-block read (CMD18) in progress; the card keeps sending consecutive sectors until the
+// stream is stopped (CMD12).  The card stays selected for the lifetime of the stream.
+pub struct ReadStream<'s, SPI: FullDuplex<u8> + SpiClock, CS: OutputPin> {
+    sdcard: &'s mut SdCard<SPI, CS>,
+    stopped: bool,
+}
 
+impl<SPI: FullDuplex<u8> + SpiClock, CS: OutputPin> ReadStream<'_, SPI, CS> {
+    pub fn read_sector(&mut self, dest: &mut [u8]) -> Result<(), SdCardError> {
+        self.sdcard.read_data(dest)
+    }
 
-pub(crate) const BUFFER_COUNT: usize = 2;
-pub(crate) const FS_BUFFER: usize = 0;
-pub(crate) const DATA_BUFFER: usize = 1;
-static mut BUFFER: [u8; BLOCK_SIZE * BUFFER_COUNT] = [0; BLOCK_SIZE * BUFFER_COUNT];
-static mut BUFFER_MODE: [DataMode; BUFFER_COUNT] = [DataMode::Idle; 2];
-static mut SECTOR_IN_BUFFER: [u32; BUFFER_COUNT] = [0; 2];
+    pub fn stop(mut self) -> Result<(), SdCardError> {
+        self.stop_helper()
+    }
 
-pub(crate) struct Block<T> {
-    buffer_index: usize,
-    old_buffer_mode: DataMode,
-    object: PhantomData<T>,
+    fn stop_helper(&mut self) -> Result<(), SdCardError> {
+        self.stopped = true;
+        let res = match self.sdcard.send_card_command(SdCommand::ReadStop, 0) {
+            Ok(()) => self.sdcard.wait_not_busy(SD_READ_TIMEOUT_MS),
+            Err(e) => Err(e),
+        };
+        self.sdcard.unselect();
+        res
+    }
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
-pub(crate) enum DataMode {
-    Idle,
-    Locked,
-    Read,
-    Write,
+impl<SPI: FullDuplex<u8> + SpiClock, CS: OutputPin> Drop for ReadStream<'_, SPI, CS> {
+    fn drop(&mut self) {
+        if !self.stopped {
+            // Nothing we can do about an error here; the next command will fail if the card is
+            // in a bad state
+            let _ = self.stop_helper();
+        }
+    }
 }
 
+// A multi-block write (CMD25) in progress; each sector is sent with its own data token, and the
+// stream is ended with the stop-tran token.  The card stays selected for the lifetime of the
+// stream.
+pub struct WriteStream<'s, SPI: FullDuplex<u8> + SpiClock, CS: OutputPin> {
+    sdcard: &'s mut SdCard<SPI, CS>,
+    finished: bool,
+}
 
-impl<T> Block<T> {
-    pub(crate) fn new(buffer_index: usize) -> Block<T> {
-        let old_buffer_mode: DataMode;
-        unsafe {
-            old_buffer_mode = BUFFER_MODE[buffer_index];
-            BUFFER_MODE[buffer_index] = DataMode::Locked;
-        }
-        Block { buffer_index, old_buffer_mode, object: PhantomData }
+impl<SPI: FullDuplex<u8> + SpiClock, CS: OutputPin> WriteStream<'_, SPI, CS> {
+    pub fn write_sector(&mut self, src: &[u8]) -> Result<(), SdCardError> {
+        self.sdcard.write_data(WRITE_MULTIPLE_TOKEN, src)
     }
 
-    pub(crate) fn get(&self) -> &'static T {
-        unsafe {
-            core::mem::transmute(BUFFER[self.buffer_index * BLOCK_SIZE..(self.buffer_index + 1) * BLOCK_SIZE].as_ptr())
-        }
+    pub fn finish(mut self) -> Result<(), SdCardError> {
+        self.finish_helper()
+    }
+
+    fn finish_helper(&mut self) -> Result<(), SdCardError> {
+        self.finished = true;
+        let res = self
+            .sdcard
+            .transfer(STOP_TRAN_TOKEN)
+            // The card starts programming one byte after the stop-tran token
+            .and_then(|_| self.sdcard.transfer(0xff))
+            .and_then(|_| self.sdcard.wait_not_busy(SD_WRITE_TIMEOUT_MS));
+        let res = self.sdcard.check_status(res);
+        self.sdcard.unselect();
+        res
     }
 }
 
-impl<T> Drop for Block<T> {
+impl<SPI: FullDuplex<u8> + SpiClock, CS: OutputPin> Drop for WriteStream<'_, SPI, CS> {
     fn drop(&mut self) {
-        unsafe {
-            BUFFER_MODE[self.buffer_index] = self.old_buffer_mode;
+        if !self.finished {
+            let _ = self.finish_helper();
         }
     }
 }
 
-#[inline(always)]
-fn is_locked(buffer_index: usize) -> bool {
-    unsafe { BUFFER_MODE[buffer_index] == DataMode::Locked }
-}
+impl<SPI, CS> SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8> + SpiClock,
+    CS: OutputPin,
+{
+    pub fn read_stream(&mut self, start_sector: u32) -> Result<ReadStream<'_, SPI, CS>, SdCardError> {
+        self.select()?;
+        if let Err(e) = self.send_card_command(SdCommand::ReadMultipleBlocks, self.sector_address(start_sector)) {
+            self.unselect();
+            return Err(e);
+        }
+        Ok(ReadStream { sdcard: self, stopped: false })
+    }
+
+    pub fn write_stream(
+        &mut self,
+        start_sector: u32,
+        sector_count: u32,
+    ) -> Result<WriteStream<'_, SPI, CS>, SdCardError> {
+        self.select()?;
+
+        // Telling the card how many sectors are coming lets it pre-erase them, which makes the
+        // write much faster; MMC cards don't support this, but the write still works without it
+        let res = match self.send_card_app_command(SdAppCommand::SetWriteBlockEraseCount, sector_count) {
+            Ok(_) => self.send_card_command(SdCommand::WriteMultipleBlocks, self.sector_address(start_sector)),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            self.unselect();
+            return Err(e);
+        }
+        Ok(WriteStream { sdcard: self, finished: false })
+    }
 
-#[inline(always)]
-fn read_required(buffer_index: usize, sector: u32) -> bool {
-    unsafe { BUFFER_MODE[buffer_index] != DataMode::Read || SECTOR_IN_BUFFER[buffer_index] != sector }
+    pub(crate) fn read_sector(&mut self, sector: u32, dest: &mut [u8]) -> Result<(), SdCardError> {
+        let mut retries = 0;
+        loop {
+            self.select()?;
+            let res = match self.send_card_command(SdCommand::ReadBlock, self.sector_address(sector)) {
+                Ok(()) => self.read_data(dest),
+                Err(e) => Err(e),
+            };
+            self.unselect();
+
+            match res {
+                Err(SdCardError::ReadCRCError) if retries < self.crc_retry_count => retries += 1,
+                _ => return res,
+            }
+        }
+    }
+
+    pub(crate) fn write_sector(&mut self, sector: u32, src: &[u8]) -> Result<(), SdCardError> {
+        self.select()?;
+        let res = match self.send_card_command(SdCommand::WriteBlock, self.sector_address(sector)) {
+            Ok(()) => {
+                let res = self.write_data(DATA_START_SECTOR, src);
+                self.check_status(res)
+            },
+            Err(e) => Err(e),
+        };
+        self.unselect();
+        res
+    }
 }
 
-impl<CSPIN: PinOps> SdCard<CSPIN> {
-    pub(crate) fn read_sector_as<T>(&mut self, buffer_index: usize, sector: u32) -> Result<Block<T>, SdCardError> {
-        if buffer_index > BUFFER_COUNT {
-            panic!();
+impl<SPI, CS> BlockDevice for SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8> + SpiClock,
+    CS: OutputPin,
+{
+    type Error = SdCardError;
+
+    fn read_sectors(&mut self, start_sector: u32, dest: &mut [u8]) -> Result<(), SdCardError> {
+        if dest.len() <= BLOCK_SIZE {
+            return self.read_sector(start_sector, dest);
         }
-        if is_locked(buffer_index) {
-            return Err(SdCardError::DataBufferLocked);
-        } else if read_required(buffer_index, sector) {
-            self.select();
-            self.send_card_command(SdCommand::ReadBlock, sector)?;
-            unsafe {
-                BUFFER_MODE[buffer_index] = DataMode::Read;
-                SECTOR_IN_BUFFER[buffer_index] = sector;
-                self.read_data(&mut BUFFER[buffer_index * BLOCK_SIZE..(buffer_index + 1) * BLOCK_SIZE])?;
+
+        // If a sector comes back corrupted, stop the stream and start a new one at that sector
+        let (sector_total, mut sectors_read) = (dest.len() / BLOCK_SIZE, 0);
+        let mut retries = 0;
+        while sectors_read < sector_total {
+            let crc_retry_count = self.crc_retry_count;
+            let mut stream = self.read_stream(start_sector + sectors_read as u32)?;
+            for sector_data in dest[sectors_read * BLOCK_SIZE..].chunks_mut(BLOCK_SIZE) {
+                match stream.read_sector(sector_data) {
+                    Ok(()) => sectors_read += 1,
+                    Err(SdCardError::ReadCRCError) if retries < crc_retry_count => {
+                        retries += 1;
+                        break;
+                    },
+                    Err(e) => return Err(e),
+                }
             }
-            self.unselect();
+            stream.stop()?;
         }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, start_sector: u32, src: &[u8]) -> Result<(), SdCardError> {
+        if src.len() <= BLOCK_SIZE {
+            return self.write_sector(start_sector, src);
+        }
+
+        let mut stream = self.write_stream(start_sector, (src.len() / BLOCK_SIZE) as u32)?;
+        for sector_data in src.chunks(BLOCK_SIZE) {
+            if let Err(e) = stream.write_sector(sector_data) {
+                // Ending the stream lets us ask the card why the write failed
+                return stream.finish().and(Err(e));
+            }
+        }
+        stream.finish()
+    }
+
+    fn erase_sectors(&mut self, start_sector: u32, sector_count: u32) -> Result<(), SdCardError> {
+        if sector_count == 0 {
+            return Ok(());
+        }
+        let end_sector = match start_sector.checked_add(sector_count - 1) {
+            Some(end_sector) => end_sector,
+            None => return Err(SdCardError::ParameterError),
+        };
+        match self.erase(start_sector, end_sector) {
+            // Some cards can only erase whole groups of sectors; the erase is just a hint, so
+            // it's fine to leave the data there
+            Err(SdCardError::AddressError) => Ok(()),
+            res => res,
+        }
+    }
+
+    fn sector_count(&mut self) -> Result<u32, SdCardError> {
+        Ok(self.read_card_specific_data()?.sector_count())
+    }
 
-        Ok(Block::new(buffer_index))
+    fn erase_block_sectors(&mut self) -> Result<u32, SdCardError> {
+        self.au_sectors()
     }
 }
@@ -1,17 +1,13 @@
-use super::SdCardError;
-use atmega_hal::spi::{
-    ChipSelectPin,
-    Spi,
-};
-use avr_hal_generic::{
-    port::PinOps,
-    prelude::*,
-    spi,
+use super::{
+    constants::*,
+    erase::EraseInfo,
+    spi::SpiClock,
+    SdCardError,
 };
 use core::cell::RefCell;
-use embedded_hal::spi::{
-    FullDuplex,
-    MODE_0,
+use embedded_hal::{
+    digital::v2::OutputPin,
+    spi::FullDuplex,
 };
 
 pub enum SdVersion {
@@ -19,68 +15,104 @@ pub enum SdVersion {
     Two { sdhc: bool },
 }
 
-pub struct SdCard<CSPIN: PinOps> {
+// The card is driven over any SPI bus that implements the embedded-hal traits, with its own
+// chip-select pin; the bus should be set up in SPI mode 0, most significant bit first.
+//
+// These are the embedded-hal 0.2 traits, because that's all avr-hal implements.  When this moves
+// to 1.0 it has to be SpiBus plus an OutputPin rather than SpiDevice: the card needs clocks with
+// chip select high while it powers up, and chip select held low across a whole multi-block read
+// or write, neither of which fits SpiDevice's one-transaction-at-a-time model.  Only transfer()
+// and the bounds on SPI talk to the bus directly.
+pub struct SdCard<SPI, CS> {
     pub version: SdVersion,
     pub(crate) millis: fn() -> u32,
-    spi: Spi,
-    cs_pin: ChipSelectPin<CSPIN>,
+    pub(crate) crc_retry_count: u8,
+    pub(crate) erase_info: Option<EraseInfo>,
+    spi: SPI,
+    cs_pin: CS,
 }
 
-impl<CSPIN: PinOps> SdCard<CSPIN> {
-    pub fn new(
-        spi: Spi,
-        cs_pin: ChipSelectPin<CSPIN>,
-        millis: fn() -> u32,
-    ) -> Result<RefCell<SdCard<CSPIN>>, SdCardError> {
+impl<SPI, CS> SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8> + SpiClock,
+    CS: OutputPin,
+{
+    pub fn new(spi: SPI, cs_pin: CS, millis: fn() -> u32) -> Result<RefCell<SdCard<SPI, CS>>, SdCardError> {
         let mut sdcard = SdCard {
             version: SdVersion::Two { sdhc: false },
             spi,
             cs_pin,
             millis,
+            crc_retry_count: SD_CRC_RETRY_COUNT,
+            erase_info: None,
         };
 
+        // Cards have to be initialized with a clock between 100 and 400kHz
+        sdcard.spi.set_init_clock().map_err(|_| SdCardError::SpiError)?;
+
         // Need to hold CS and MOSI high for at least 74 clock cycles;
         // each transfer takes 8 clock cycles so repeating for 10 times is sufficient
-        sdcard.cs_pin.set_high().void_unwrap();
+        sdcard.cs_pin.set_high().map_err(|_| SdCardError::ChipSelectError)?;
         for _ in 0..10 {
-            sdcard.transfer(0xff);
+            sdcard.transfer(0xff)?;
         }
 
-        sdcard.select();
-        sdcard.init_spi()?;
-        sdcard.check_sd_version()?;
-        sdcard.enable_crc()?;
-        sdcard.check_and_enable_sdhc()?;
+        sdcard.select()?;
+        let res = sdcard.init();
         sdcard.unselect();
+        res?;
 
-        // Once initialization is complete we can bump the SPI speed up to max
-        // (cards support up to 25MHz, OscfOver2 = 8MHz)
-        nb::block!(sdcard.spi.reconfigure(spi::Settings {
-            data_order: spi::DataOrder::MostSignificantFirst,
-            clock: spi::SerialClockRate::OscfOver2,
-            mode: MODE_0,
-        }))
-        .void_unwrap();
+        // Once initialization is complete we can bump the SPI speed up to max (cards support
+        // up to 25MHz)
+        sdcard.spi.set_fast_clock().map_err(|_| SdCardError::SpiError)?;
 
         Ok(RefCell::new(sdcard))
     }
 
+    // Number of times a data block is re-read from the card if its CRC doesn't match
+    pub fn set_crc_retry_count(&mut self, crc_retry_count: u8) {
+        self.crc_retry_count = crc_retry_count;
+    }
+
+    // Give back the SPI bus and chip-select pin, e.g. to share the bus with another device
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs_pin)
+    }
+
+    // Standard capacity cards are byte-addressed, SDHC/SDXC cards are block-addressed
     #[inline(always)]
-    pub(crate) fn select(&mut self) {
+    pub(crate) fn sector_address(&self, sector: u32) -> u32 {
+        match self.version {
+            SdVersion::Two { sdhc: true } => sector,
+            _ => sector << 9,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn select(&mut self) -> Result<(), SdCardError> {
         // Set CS to low to indicate we're talking
-        self.cs_pin.set_low().void_unwrap();
+        self.cs_pin.set_low().map_err(|_| SdCardError::ChipSelectError)
     }
 
     #[inline(always)]
     pub(crate) fn unselect(&mut self) {
-        // Set CS to high when we're all finished
-        self.cs_pin.set_high().void_unwrap();
+        // Set CS to high when we're all finished; this is called on error paths too, so if it
+        // fails, the card just won't respond to the next command
+        let _ = self.cs_pin.set_high();
+    }
+
+    pub(crate) fn transfer(&mut self, byte: u8) -> Result<u8, SdCardError> {
+        nb::block!(self.spi.send(byte)).map_err(|_| SdCardError::SpiError)?;
+        nb::block!(self.spi.read()).map_err(|_| SdCardError::SpiError)
     }
 
-    pub(crate) fn transfer(&mut self, byte: u8) -> u8 {
-        nb::block!(self.spi.send(byte)).void_unwrap();
-        nb::block!(self.spi.read()).void_unwrap()
+    fn init(&mut self) -> Result<(), SdCardError> {
+        self.init_spi()?;
+        self.check_sd_version()?;
+        self.enable_crc()?;
+        self.check_and_enable_sdhc()?;
+        self.set_block_len()
     }
 }
 
-pub type SdCardRef<'s, CSPIN> = &'s RefCell<SdCard<CSPIN>>;
+pub type SdCardRef<'s, SPI, CS> = &'s RefCell<SdCard<SPI, CS>>;
@@ -0,0 +1,44 @@
+#[cfg(target_arch = "avr")]
+use atmega_hal::spi::Spi;
+#[cfg(target_arch = "avr")]
+use avr_hal_generic::spi;
+#[cfg(target_arch = "avr")]
+use embedded_hal::spi::MODE_0;
+#[cfg(target_arch = "avr")]
+use void::Void;
+
+
+// Cards have to be initialized with a slow SPI clock, and can then be switched over to a fast
+// one; every platform's HAL does this differently, so the bus has to say how
+pub trait SpiClock {
+    type Error;
+
+    // At most 400kHz
+    fn set_init_clock(&mut self) -> Result<(), Self::Error>;
+
+    // As fast as the bus will go, up to 25MHz
+    fn set_fast_clock(&mut self) -> Result<(), Self::Error>;
+}
+
+#[cfg(target_arch = "avr")]
+impl SpiClock for Spi {
+    type Error = Void;
+
+    fn set_init_clock(&mut self) -> Result<(), Void> {
+        // 250kHz on a 16MHz part
+        nb::block!(self.reconfigure(spi::Settings {
+            data_order: spi::DataOrder::MostSignificantFirst,
+            clock: spi::SerialClockRate::OscfOver64,
+            mode: MODE_0,
+        }))
+    }
+
+    fn set_fast_clock(&mut self) -> Result<(), Void> {
+        // 8MHz on a 16MHz part
+        nb::block!(self.reconfigure(spi::Settings {
+            data_order: spi::DataOrder::MostSignificantFirst,
+            clock: spi::SerialClockRate::OscfOver2,
+            mode: MODE_0,
+        }))
+    }
+}
@@ -0,0 +1,175 @@
+use super::{
+    cmd::SdCommand,
+    spi::SpiClock,
+    SdCard,
+    SdCardError,
+};
+use embedded_hal::{
+    digital::v2::OutputPin,
+    spi::FullDuplex,
+};
+
+
+// R1 response bits
+const R1_IDLE: u8 = 0x01;
+const R1_ERASE_RESET: u8 = 0x02;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_CRC_ERROR: u8 = 0x08;
+const R1_ERASE_SEQUENCE_ERROR: u8 = 0x10;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+const R1_PARAMETER_ERROR: u8 = 0x40;
+
+// Second byte of the R2 response
+const R2_CARD_LOCKED: u8 = 0x01;
+const R2_WP_ERASE_SKIP: u8 = 0x02;
+const R2_ERROR: u8 = 0x04;
+const R2_CC_ERROR: u8 = 0x08;
+const R2_ECC_FAILED: u8 = 0x10;
+const R2_WP_VIOLATION: u8 = 0x20;
+const R2_ERASE_PARAM: u8 = 0x40;
+const R2_OUT_OF_RANGE: u8 = 0x80;
+
+// The R2 response to SEND_STATUS (CMD13): the usual R1 byte, followed by a byte of card status
+pub struct CardStatus {
+    r1: u8,
+    r2: u8,
+}
+
+impl CardStatus {
+    #[inline(always)]
+    pub fn idle(&self) -> bool {
+        self.r1 & R1_IDLE > 0
+    }
+
+    #[inline(always)]
+    pub fn erase_reset(&self) -> bool {
+        self.r1 & R1_ERASE_RESET > 0
+    }
+
+    #[inline(always)]
+    pub fn illegal_command(&self) -> bool {
+        self.r1 & R1_ILLEGAL_COMMAND > 0
+    }
+
+    #[inline(always)]
+    pub fn crc_error(&self) -> bool {
+        self.r1 & R1_CRC_ERROR > 0
+    }
+
+    #[inline(always)]
+    pub fn erase_sequence_error(&self) -> bool {
+        self.r1 & R1_ERASE_SEQUENCE_ERROR > 0
+    }
+
+    #[inline(always)]
+    pub fn address_error(&self) -> bool {
+        self.r1 & R1_ADDRESS_ERROR > 0
+    }
+
+    #[inline(always)]
+    pub fn parameter_error(&self) -> bool {
+        self.r1 & R1_PARAMETER_ERROR > 0
+    }
+
+    #[inline(always)]
+    pub fn card_locked(&self) -> bool {
+        self.r2 & R2_CARD_LOCKED > 0
+    }
+
+    // Also set if a lock/unlock command failed
+    #[inline(always)]
+    pub fn wp_erase_skip(&self) -> bool {
+        self.r2 & R2_WP_ERASE_SKIP > 0
+    }
+
+    #[inline(always)]
+    pub fn error(&self) -> bool {
+        self.r2 & R2_ERROR > 0
+    }
+
+    #[inline(always)]
+    pub fn cc_error(&self) -> bool {
+        self.r2 & R2_CC_ERROR > 0
+    }
+
+    #[inline(always)]
+    pub fn ecc_failed(&self) -> bool {
+        self.r2 & R2_ECC_FAILED > 0
+    }
+
+    #[inline(always)]
+    pub fn wp_violation(&self) -> bool {
+        self.r2 & R2_WP_VIOLATION > 0
+    }
+
+    #[inline(always)]
+    pub fn erase_param(&self) -> bool {
+        self.r2 & R2_ERASE_PARAM > 0
+    }
+
+    // Also set if the CSD was overwritten
+    #[inline(always)]
+    pub fn out_of_range(&self) -> bool {
+        self.r2 & R2_OUT_OF_RANGE > 0
+    }
+
+    // The most specific error that the card is reporting, if any
+    pub fn to_result(&self) -> Result<(), SdCardError> {
+        r1_to_result(self.r1)?;
+        match self.r2 {
+            0 => Ok(()),
+            b if b & R2_CARD_LOCKED != 0 => Err(SdCardError::CardLocked),
+            b if b & R2_WP_VIOLATION != 0 => Err(SdCardError::WriteProtectViolation),
+            b if b & R2_WP_ERASE_SKIP != 0 => Err(SdCardError::WriteProtectEraseSkip),
+            b if b & R2_ERASE_PARAM != 0 => Err(SdCardError::EraseParamError),
+            b if b & R2_OUT_OF_RANGE != 0 => Err(SdCardError::OutOfRange),
+            b if b & R2_ECC_FAILED != 0 => Err(SdCardError::ECCFailed),
+            b if b & R2_CC_ERROR != 0 => Err(SdCardError::CardControllerError),
+            _ => Err(SdCardError::CardError),
+        }
+    }
+}
+
+pub(crate) fn r1_to_result(r1: u8) -> Result<(), SdCardError> {
+    match r1 {
+        b if b & !R1_IDLE == 0 => Ok(()),
+        b if b & R1_ERASE_RESET != 0 => Err(SdCardError::EraseReset),
+        b if b & R1_ILLEGAL_COMMAND != 0 => Err(SdCardError::IllegalCommand),
+        b if b & R1_CRC_ERROR != 0 => Err(SdCardError::CRCError),
+        b if b & R1_ERASE_SEQUENCE_ERROR != 0 => Err(SdCardError::EraseSequenceError),
+        b if b & R1_ADDRESS_ERROR != 0 => Err(SdCardError::AddressError),
+        b if b & R1_PARAMETER_ERROR != 0 => Err(SdCardError::ParameterError),
+        _ => Err(SdCardError::Unknown),
+    }
+}
+
+impl<SPI, CS> SdCard<SPI, CS>
+where
+    SPI: FullDuplex<u8> + SpiClock,
+    CS: OutputPin,
+{
+    pub fn status(&mut self) -> Result<CardStatus, SdCardError> {
+        self.select()?;
+        let res = self.read_status();
+        self.unselect();
+        res
+    }
+
+    // Write and erase failures only tell us that something went wrong; ask the card for its
+    // status to find out what.  Assumes the card is already selected.
+    pub(crate) fn check_status(&mut self, res: Result<(), SdCardError>) -> Result<(), SdCardError> {
+        match res {
+            Ok(()) | Err(SdCardError::WriteError) | Err(SdCardError::Unknown) => {
+                self.read_status()?.to_result()?;
+                res
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_status(&mut self) -> Result<CardStatus, SdCardError> {
+        let r1 = self.send_card_command_r1(SdCommand::SendStatus, 0)?;
+        let r2 = self.transfer(0xff)?;
+        Ok(CardStatus { r1, r2 })
+    }
+}
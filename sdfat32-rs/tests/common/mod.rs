@@ -0,0 +1,356 @@
+// Shared pieces for the filesystem tests: a RAM disk to put volumes on, and helpers that read and
+// poke the on-disk structures directly, so the tests don't only check the library against itself
+#![allow(dead_code)]
+
+use core::{
+    cell::RefCell,
+    convert::TryInto,
+};
+use sdfat32_rs::{
+    block_device::BlockDevice,
+    fat32::{
+        FatError,
+        Mbr,
+        PartitionEntry,
+        PartitionTable,
+        SectorCache,
+        Volume,
+    },
+    sdcard::BLOCK_SIZE,
+};
+
+pub const FAT32_END_OF_CHAIN: u32 = 0x0fffffff;
+
+// Anything from 0x0ffffff8 up marks the end of a chain
+pub fn is_end_of_chain(entry: u32) -> bool {
+    entry >= 0x0ffffff8
+}
+
+pub struct RamDisk {
+    pub image: Vec<u8>,
+    pub erase_block_sectors: u32,
+    // Every read, write and erase the device has been asked to do, as (start sector, sector count)
+    pub reads: Vec<(u32, u32)>,
+    pub writes: Vec<(u32, u32)>,
+    pub erases: Vec<(u32, u32)>,
+    // Makes every write fail, like a card that's been pulled out
+    pub fail_writes: bool,
+    // Size to report instead of the image's, so tests that only touch the first few sectors can
+    // pretend to have a big card
+    pub claimed_sectors: Option<u32>,
+}
+
+impl RamDisk {
+    pub fn new(sector_count: u32, erase_block_sectors: u32) -> RefCell<RamDisk> {
+        RefCell::new(RamDisk {
+            image: vec![0; sector_count as usize * BLOCK_SIZE],
+            erase_block_sectors,
+            reads: Vec::new(),
+            writes: Vec::new(),
+            erases: Vec::new(),
+            fail_writes: false,
+            claimed_sectors: None,
+        })
+    }
+
+    fn range(&self, start_sector: u32, len: usize) -> Result<core::ops::Range<usize>, ()> {
+        let start = start_sector as usize * BLOCK_SIZE;
+        if !len.is_multiple_of(BLOCK_SIZE) || start + len > self.image.len() {
+            return Err(());
+        }
+        Ok(start..start + len)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    type Error = ();
+
+    fn read_sectors(&mut self, start_sector: u32, dest: &mut [u8]) -> Result<(), ()> {
+        let range = self.range(start_sector, dest.len())?;
+        dest.copy_from_slice(&self.image[range]);
+        self.reads.push((start_sector, (dest.len() / BLOCK_SIZE) as u32));
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, start_sector: u32, src: &[u8]) -> Result<(), ()> {
+        if self.fail_writes {
+            return Err(());
+        }
+        let range = self.range(start_sector, src.len())?;
+        self.image[range].copy_from_slice(src);
+        self.writes.push((start_sector, (src.len() / BLOCK_SIZE) as u32));
+        Ok(())
+    }
+
+    // Erased sectors read back as all 1s, so anything that relies on them being zeroed shows up
+    fn erase_sectors(&mut self, start_sector: u32, sector_count: u32) -> Result<(), ()> {
+        let range = self.range(start_sector, sector_count as usize * BLOCK_SIZE)?;
+        self.image[range].iter_mut().for_each(|b| *b = 0xff);
+        self.erases.push((start_sector, sector_count));
+        Ok(())
+    }
+
+    fn sector_count(&mut self) -> Result<u32, ()> {
+        Ok(self.claimed_sectors.unwrap_or((self.image.len() / BLOCK_SIZE) as u32))
+    }
+
+    fn erase_block_sectors(&mut self) -> Result<u32, ()> {
+        Ok(self.erase_block_sectors)
+    }
+}
+
+// FatError doesn't implement Debug or PartialEq, so results are compared by error code
+pub fn code<T>(res: Result<T, FatError>) -> Result<T, u8> {
+    res.map_err(|e| e as u8)
+}
+
+pub fn u16_at(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(image[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn u32_at(image: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+}
+
+// The parts of a FAT32 boot sector the tests care about, read straight from the image
+pub struct BootSector {
+    pub start_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fat_count: u32,
+    pub hidden_sectors: u32,
+    pub total_sectors: u32,
+    pub sectors_per_fat: u32,
+    pub root_cluster: u32,
+    pub fs_info_sector: u32,
+    pub backup_boot_sector: u32,
+    pub volume_serial: u32,
+    pub volume_label: [u8; 11],
+}
+
+impl BootSector {
+    pub fn read(image: &[u8], start_sector: u32) -> BootSector {
+        let bs = &image[start_sector as usize * BLOCK_SIZE..(start_sector as usize + 1) * BLOCK_SIZE];
+        assert_eq!(&bs[510..], &[0x55, 0xaa]);
+        assert_eq!(u16_at(bs, 11) as usize, BLOCK_SIZE);
+        BootSector {
+            start_sector,
+            sectors_per_cluster: bs[13] as u32,
+            reserved_sectors: u16_at(bs, 14) as u32,
+            fat_count: bs[16] as u32,
+            hidden_sectors: u32_at(bs, 28),
+            total_sectors: u32_at(bs, 32),
+            sectors_per_fat: u32_at(bs, 36),
+            root_cluster: u32_at(bs, 44),
+            fs_info_sector: u16_at(bs, 48) as u32,
+            backup_boot_sector: u16_at(bs, 50) as u32,
+            volume_serial: u32_at(bs, 67),
+            volume_label: bs[71..82].try_into().unwrap(),
+        }
+    }
+
+    pub fn fat_start_sector(&self, fat: u32) -> u32 {
+        self.start_sector + self.reserved_sectors + fat * self.sectors_per_fat
+    }
+
+    pub fn data_start_sector(&self) -> u32 {
+        self.fat_start_sector(self.fat_count)
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        (self.total_sectors - (self.data_start_sector() - self.start_sector)) / self.sectors_per_cluster
+    }
+
+    pub fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start_sector() + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    pub fn fat_entry(&self, image: &[u8], fat: u32, cluster: u32) -> u32 {
+        u32_at(image, self.fat_start_sector(fat) as usize * BLOCK_SIZE + cluster as usize * 4) & 0x0fffffff
+    }
+
+    pub fn set_fat_entry(&self, image: &mut [u8], cluster: u32, value: u32) {
+        for fat in 0..self.fat_count {
+            let offset = self.fat_start_sector(fat) as usize * BLOCK_SIZE + cluster as usize * 4;
+            image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    pub fn free_clusters(&self, image: &[u8]) -> u32 {
+        (2..self.cluster_count() + 2).filter(|c| self.fat_entry(image, 0, *c) == 0).count() as u32
+    }
+
+    // (free count, next free hint) from the FSInfo sector
+    pub fn fs_info(&self, image: &[u8]) -> (u32, u32) {
+        let offset = (self.start_sector + self.fs_info_sector) as usize * BLOCK_SIZE;
+        assert_eq!(u32_at(image, offset), 0x41615252);
+        assert_eq!(u32_at(image, offset + 484), 0x61417272);
+        assert_eq!(u32_at(image, offset + 508), 0xaa550000);
+        (u32_at(image, offset + 488), u32_at(image, offset + 492))
+    }
+
+    // Put a short file name entry in slot `index` of the first sector of a directory cluster
+    #[allow(clippy::too_many_arguments)]
+    pub fn put_dir_entry(
+        &self,
+        image: &mut [u8],
+        dir_cluster: u32,
+        index: usize,
+        name: &[u8; 11],
+        attributes: u8,
+        first_cluster: u32,
+        size: u32,
+    ) {
+        let offset = self.cluster_sector(dir_cluster) as usize * BLOCK_SIZE + index * 32;
+        let entry = &mut image[offset..offset + 32];
+        entry.iter_mut().for_each(|b| *b = 0);
+        entry[0..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+    }
+}
+
+// Partition a RAM disk with a single FAT32 partition and format it
+pub fn format_ram_disk<const N: usize>(
+    sector_count: u32,
+    erase_block_sectors: u32,
+    volume_label: &[u8; 11],
+) -> (RefCell<RamDisk>, PartitionEntry, Volume<N>) {
+    let device = RamDisk::new(sector_count, erase_block_sectors);
+    let cache = SectorCache::new();
+    code(Mbr::write_fat32_part_info(&device, &cache)).unwrap();
+    let table = code(PartitionTable::read(&device, &cache)).unwrap();
+    let partition_entry = code(table.partition(&device, &cache, 0)).unwrap().unwrap();
+    let volume = code(Volume::format(&device, cache, 0, &partition_entry, volume_label, 0x1234abcd)).unwrap();
+    (device, partition_entry, volume)
+}
+
+pub const GPT_ENTRY_COUNT: u32 = 128;
+pub const GPT_ENTRY_SIZE: usize = 128;
+
+// Sectors taken by the entry array, which comes straight after the primary header and straight
+// before the backup one
+pub const GPT_ENTRY_SECTORS: u64 = GPT_ENTRY_COUNT as u64 * GPT_ENTRY_SIZE as u64 / BLOCK_SIZE as u64;
+
+pub struct GptPartition {
+    pub type_guid: [u8; 16],
+    pub unique_guid: [u8; 16],
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub name: &'static str,
+}
+
+// CRC-32 as zip and Ethernet use it, a bit at a time
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// A GUID as it's written on disk, from the way it's usually written down: the first three
+// groups are little-endian and the rest are bytes in order
+pub fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let mut guid = [0; 16];
+    guid[0..4].copy_from_slice(&a.to_le_bytes());
+    guid[4..6].copy_from_slice(&b.to_le_bytes());
+    guid[6..8].copy_from_slice(&c.to_le_bytes());
+    guid[8..16].copy_from_slice(&d);
+    guid
+}
+
+pub fn basic_data_guid() -> [u8; 16] {
+    guid(0xebd0a0a2, 0xb9e5, 0x4433, [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7])
+}
+
+pub fn linux_data_guid() -> [u8; 16] {
+    guid(0x0fc63daf, 0x8483, 0x4772, [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4])
+}
+
+pub fn gpt_disk_guid() -> [u8; 16] {
+    guid(0x12345678, 0x9abc, 0xdef0, [1, 2, 3, 4, 5, 6, 7, 8])
+}
+
+// Put a protective MBR and both copies of a GPT with room for 128 partitions on a RAM disk
+pub fn put_gpt(device: &RefCell<RamDisk>, partitions: &[GptPartition]) {
+    let image = &mut device.borrow_mut().image;
+    let last_lba = (image.len() / BLOCK_SIZE) as u64 - 1;
+
+    let mbr = &mut image[..BLOCK_SIZE];
+    mbr.iter_mut().for_each(|b| *b = 0);
+    mbr[446..454].copy_from_slice(&[0x00, 0x00, 0x02, 0x00, 0xee, 0xfe, 0xff, 0xff]);
+    mbr[454..458].copy_from_slice(&1u32.to_le_bytes());
+    mbr[458..462].copy_from_slice(&(last_lba.min(u32::MAX as u64) as u32).to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let mut entries = vec![0u8; GPT_ENTRY_COUNT as usize * GPT_ENTRY_SIZE];
+    for (entry, partition) in entries.chunks_mut(GPT_ENTRY_SIZE).zip(partitions) {
+        entry[0..16].copy_from_slice(&partition.type_guid);
+        entry[16..32].copy_from_slice(&partition.unique_guid);
+        entry[32..40].copy_from_slice(&partition.first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&partition.last_lba.to_le_bytes());
+        for (i, c) in partition.name.encode_utf16().enumerate() {
+            entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+
+    let backup_entries_lba = last_lba - GPT_ENTRY_SECTORS;
+    for (header_lba, entries_lba) in [(1, 2), (last_lba, backup_entries_lba)].iter() {
+        let offset = *entries_lba as usize * BLOCK_SIZE;
+        image[offset..offset + entries.len()].copy_from_slice(&entries);
+        put_gpt_header(image, *header_lba, *entries_lba, GPT_ENTRY_COUNT, crc32(&entries));
+    }
+}
+
+// Write a GPT header, with its CRC, into sector `header_lba`
+pub fn put_gpt_header(image: &mut [u8], header_lba: u64, entries_lba: u64, entry_count: u32, entries_crc32: u32) {
+    let last_lba = (image.len() / BLOCK_SIZE) as u64 - 1;
+    let alternate_lba = if header_lba == 1 { last_lba } else { 1 };
+    let mut header = [0u8; 92];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&header_lba.to_le_bytes());
+    header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+    header[40..48].copy_from_slice(&(2 + GPT_ENTRY_SECTORS).to_le_bytes());
+    header[48..56].copy_from_slice(&(last_lba - 1 - GPT_ENTRY_SECTORS).to_le_bytes());
+    header[56..72].copy_from_slice(&gpt_disk_guid());
+    header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    header[80..84].copy_from_slice(&entry_count.to_le_bytes());
+    header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc32.to_le_bytes());
+    let header_crc32 = crc32(&header);
+    header[16..20].copy_from_slice(&header_crc32.to_le_bytes());
+
+    let sector = &mut image[header_lba as usize * BLOCK_SIZE..(header_lba as usize + 1) * BLOCK_SIZE];
+    sector.iter_mut().for_each(|b| *b = 0);
+    sector[..92].copy_from_slice(&header);
+}
+
+pub const PTYPE_FAT32_LBA: u8 = 0x0c;
+pub const PTYPE_EXTENDED_LBA: u8 = 0x0f;
+pub const PTYPE_LINUX: u8 = 0x83;
+
+// Fill in slot `slot` of the MBR or EBR at `sector` (LBA fields only) and give it a boot signature
+pub fn put_partition_entry(
+    image: &mut [u8],
+    sector: u32,
+    slot: usize,
+    ptype: u8,
+    start_sector: u32,
+    total_sectors: u32,
+) {
+    let sector = &mut image[sector as usize * BLOCK_SIZE..(sector as usize + 1) * BLOCK_SIZE];
+    let entry = &mut sector[446 + slot * 16..446 + (slot + 1) * 16];
+    entry.iter_mut().for_each(|b| *b = 0);
+    entry[4] = ptype;
+    entry[8..12].copy_from_slice(&start_sector.to_le_bytes());
+    entry[12..16].copy_from_slice(&total_sectors.to_le_bytes());
+    sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+}
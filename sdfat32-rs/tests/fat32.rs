@@ -0,0 +1,1019 @@
+// Filesystem tests on a RAM disk
+mod common;
+
+use common::*;
+use core::cell::RefCell;
+use sdfat32_rs::fat32::{
+    constants::{
+        O_RDONLY,
+        O_RDWR,
+    },
+    FatError,
+    Guid,
+    Mbr,
+    PartitionEntry,
+    PartitionInfo,
+    PartitionTable,
+    PartitionType,
+    SectorCache,
+    Volume,
+};
+
+// Big enough for FAT32 with single-sector clusters
+const SMALL_DISK_SECTORS: u32 = 70000;
+
+// Big enough that erase-block alignment leaves room for FAT32
+const LARGE_DISK_SECTORS: u32 = 200000;
+const LARGE_DISK_ERASE_BLOCK: u32 = 8192;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_LABEL: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+
+#[test]
+fn open_sets_file_and_directory_attributes() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    {
+        let image = &mut device.borrow_mut().image;
+        let bs = BootSector::read(image, partition_entry.start_sector());
+        bs.put_dir_entry(image, bs.root_cluster, 0, b"ARCHIVE TXT", ATTR_ARCHIVE, 0, 0);
+        bs.put_dir_entry(image, bs.root_cluster, 1, b"PLAIN   TXT", 0, 0, 0);
+        bs.put_dir_entry(image, bs.root_cluster, 2, b"READONLYTXT", ATTR_READ_ONLY, 0, 0);
+        bs.put_dir_entry(image, bs.root_cluster, 3, b"SUBDIR     ", ATTR_DIRECTORY, 3, 0);
+        bs.set_fat_entry(image, 3, FAT32_END_OF_CHAIN);
+    }
+    let volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+
+    // Regular files have no attribute bit of their own on disk, whatever else is set
+    for name in [&b"ARCHIVE.TXT"[..], b"PLAIN.TXT", b"READONLY.TXT"].iter() {
+        let file = code(volume.open_by_name(&device, name, O_RDONLY)).unwrap();
+        assert!(file.is_open());
+        assert!(file.is_file());
+        assert!(!file.is_directory());
+    }
+
+    let dir = code(volume.open_by_name(&device, b"SUBDIR", O_RDONLY)).unwrap();
+    assert!(dir.is_open());
+    assert!(dir.is_directory());
+    assert!(!dir.is_file());
+    assert!(!dir.is_root());
+
+    let root = volume.open_root(O_RDONLY);
+    assert!(root.is_directory());
+    assert!(root.is_root());
+    assert!(!root.is_file());
+}
+
+#[test]
+fn allocate_one_cluster_and_a_chain() {
+    let (device, partition_entry, mut volume) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+
+    // The root directory has cluster 2, so the first free one is right after it
+    let first = code(volume.allocate_clusters(&device, 0, 1)).unwrap();
+    assert_eq!(first, 3);
+    let next = code(volume.allocate_clusters(&device, first, 4)).unwrap();
+    assert_eq!(next, 4);
+    code(volume.sync(&device)).unwrap();
+
+    let image = &device.borrow().image;
+    for fat in 0..bs.fat_count {
+        for cluster in 3..7 {
+            assert_eq!(bs.fat_entry(image, fat, cluster), cluster + 1);
+        }
+        assert!(is_end_of_chain(bs.fat_entry(image, fat, 7)));
+        assert_eq!(bs.fat_entry(image, fat, 8), 0);
+    }
+    assert_eq!(bs.free_clusters(image), bs.cluster_count() - 6);
+}
+
+#[test]
+fn allocate_prefers_a_contiguous_run() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+
+    // Clusters 3 to 12 are in use apart from a two-cluster hole at 5 and 6
+    for cluster in (3..13).filter(|c| *c != 5 && *c != 6) {
+        bs.set_fat_entry(&mut device.borrow_mut().image, cluster, FAT32_END_OF_CHAIN);
+    }
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+
+    // Four clusters don't fit in the hole, so they go after the used ones in a single run
+    let first = code(volume.allocate_clusters(&device, 0, 4)).unwrap();
+    assert_eq!(first, 13);
+
+    // Extending a chain carries straight on from its last cluster rather than filling the hole
+    let next = code(volume.allocate_clusters(&device, 16, 2)).unwrap();
+    assert_eq!(next, 17);
+
+    code(volume.sync(&device)).unwrap();
+
+    let image = &device.borrow().image;
+    for cluster in 13..18 {
+        assert_eq!(bs.fat_entry(image, 0, cluster), cluster + 1);
+    }
+    assert!(is_end_of_chain(bs.fat_entry(image, 0, 18)));
+    assert_eq!(bs.fat_entry(image, 0, 5), 0);
+    assert_eq!(bs.fat_entry(image, 0, 6), 0);
+}
+
+#[test]
+fn allocate_updates_both_fats_and_fs_info() {
+    let (device, partition_entry, mut volume) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let free = bs.cluster_count() - 1;
+    assert_eq!(bs.fs_info(&device.borrow().image), (free, 2));
+
+    // Enough clusters to span several FAT sectors
+    let first = code(volume.allocate_clusters(&device, 0, 300)).unwrap();
+    code(volume.sync(&device)).unwrap();
+
+    let image = &device.borrow().image;
+    let fat_bytes = bs.sectors_per_fat as usize * 512;
+    let fat0 = bs.fat_start_sector(0) as usize * 512;
+    let fat1 = bs.fat_start_sector(1) as usize * 512;
+    assert!(image[fat0..fat0 + fat_bytes] == image[fat1..fat1 + fat_bytes]);
+    assert!(is_end_of_chain(bs.fat_entry(image, 1, first + 299)));
+
+    // The hint is the last cluster handed out
+    assert_eq!(bs.fs_info(image), (free - 300, first + 299));
+    assert_eq!(bs.free_clusters(image), free - 300);
+    assert_eq!(volume.free_space(), Some((free as u64 - 300) * 512));
+}
+
+#[test]
+fn free_chain_and_reuse_it() {
+    let (device, partition_entry, mut volume) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let free = bs.cluster_count() - 1;
+
+    let first = code(volume.allocate_clusters(&device, 0, 3)).unwrap();
+    code(volume.allocate_clusters(&device, 0, 2)).unwrap();
+    code(volume.allocate_clusters(&device, first + 2, 2)).unwrap();
+    code(volume.sync(&device)).unwrap();
+    assert_eq!(bs.fat_entry(&device.borrow().image, 0, first + 2), first + 5);
+
+    device.borrow_mut().erases.clear();
+    code(volume.free_cluster_chain(&device, first)).unwrap();
+    code(volume.sync(&device)).unwrap();
+
+    {
+        let disk = device.borrow();
+        for cluster in (first..first + 3).chain(first + 5..first + 7) {
+            assert_eq!(bs.fat_entry(&disk.image, 0, cluster), 0);
+            assert_eq!(bs.fat_entry(&disk.image, 1, cluster), 0);
+        }
+        assert_eq!(bs.fat_entry(&disk.image, 0, first + 3), first + 4);
+
+        // Freed clusters are discarded a run at a time
+        let sector = |cluster| bs.cluster_sector(cluster);
+        assert_eq!(disk.erases, vec![(sector(first), 3), (sector(first + 5), 2)]);
+
+        // The search goes back to the first freed cluster so the space gets used again
+        assert_eq!(bs.fs_info(&disk.image), (free - 2, first - 1));
+    }
+
+    let reused = code(volume.allocate_clusters(&device, 0, 3)).unwrap();
+    assert_eq!(reused, first);
+}
+
+#[test]
+fn freeing_a_looping_chain_frees_each_cluster_once() {
+    let (device, partition_entry, mut volume) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let first = code(volume.allocate_clusters(&device, 0, 2)).unwrap();
+    code(volume.unmount(&device)).unwrap();
+
+    // first -> first + 1 -> first -> ...
+    bs.set_fat_entry(&mut device.borrow_mut().image, first + 1, first);
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+    let free = volume.free_space().unwrap();
+    let cluster_bytes = bs.sectors_per_cluster as u64 * 512;
+    assert_eq!(code(volume.free_cluster_chain(&device, first)), Err(FatError::CorruptFat as u8));
+    assert_eq!(volume.free_space(), Some(free + 2 * cluster_bytes));
+
+    // Nothing left to free, so nothing changes
+    assert_eq!(code(volume.free_cluster_chain(&device, first)), Err(FatError::CorruptFat as u8));
+    assert_eq!(volume.free_space(), Some(free + 2 * cluster_bytes));
+
+    code(volume.sync(&device)).unwrap();
+    let image = &device.borrow().image;
+    assert_eq!(bs.fat_entry(image, 0, first), 0);
+    assert_eq!(bs.fat_entry(image, 0, first + 1), 0);
+    assert_eq!(bs.fs_info(image).0, bs.free_clusters(image));
+    assert_eq!(bs.fs_info(image).0, bs.cluster_count() - 1);
+}
+
+#[test]
+fn freed_clusters_are_discarded_after_the_fat_is_written() {
+    let (device, partition_entry, mut volume) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let first = code(volume.allocate_clusters(&device, 0, 4)).unwrap();
+    code(volume.sync(&device)).unwrap();
+
+    // The FAT can't be written, so the clusters mustn't be discarded either; the chain is still
+    // there on the device, and so is its data
+    device.borrow_mut().erases.clear();
+    device.borrow_mut().fail_writes = true;
+    assert_eq!(code(volume.free_cluster_chain(&device, first)), Err(FatError::BlockDeviceFailed as u8));
+    assert!(device.borrow().erases.is_empty());
+    assert_eq!(bs.fat_entry(&device.borrow().image, 0, first), first + 1);
+
+    device.borrow_mut().fail_writes = false;
+    code(volume.sync(&device)).unwrap();
+    for cluster in first..first + 4 {
+        assert_eq!(bs.fat_entry(&device.borrow().image, 0, cluster), 0);
+    }
+}
+
+#[test]
+fn allocate_on_a_full_volume() {
+    let (device, partition_entry, mut volume) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let free = bs.cluster_count() - 1;
+
+    // The free count is known, so this fails without touching the FAT
+    assert_eq!(code(volume.allocate_clusters(&device, 0, free + 1)), Err(FatError::VolumeFull as u8));
+
+    // Use up everything but three clusters behind FSInfo's back, so its free count is wrong
+    for cluster in (3..bs.cluster_count() + 2).filter(|c| *c < 100 || *c > 102) {
+        bs.set_fat_entry(&mut device.borrow_mut().image, cluster, FAT32_END_OF_CHAIN);
+    }
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+
+    // The three clusters get linked onto the root directory before the allocator finds out there
+    // aren't any more; all of that has to be undone
+    assert_eq!(code(volume.allocate_clusters(&device, bs.root_cluster, 5)), Err(FatError::VolumeFull as u8));
+    code(volume.sync(&device)).unwrap();
+    {
+        let image = &device.borrow().image;
+        assert!(is_end_of_chain(bs.fat_entry(image, 0, bs.root_cluster)));
+        for cluster in 100..103 {
+            assert_eq!(bs.fat_entry(image, 0, cluster), 0);
+            assert_eq!(bs.fat_entry(image, 1, cluster), 0);
+        }
+        assert_eq!(bs.fs_info(image).0, free);
+    }
+
+    // What's there can still be had
+    assert_eq!(code(volume.allocate_clusters(&device, 0, 3)), Ok(100));
+}
+
+#[test]
+fn fs_info_is_written_again_after_a_failed_sync() {
+    let (device, partition_entry, mut volume) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let free = bs.cluster_count() - 1;
+
+    let first = code(volume.allocate_clusters(&device, 0, 10)).unwrap();
+    device.borrow_mut().fail_writes = true;
+    assert_eq!(code(volume.sync(&device)), Err(FatError::BlockDeviceFailed as u8));
+    assert_eq!(bs.fs_info(&device.borrow().image), (free, 2));
+
+    device.borrow_mut().fail_writes = false;
+    code(volume.sync(&device)).unwrap();
+    assert_eq!(bs.fs_info(&device.borrow().image), (free - 10, first + 9));
+}
+
+#[test]
+fn open_volume_rejects_a_volume_smaller_than_its_fats() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+
+    // Total sector count that doesn't even cover the reserved sectors and the FATs
+    let offset = partition_entry.start_sector() as usize * 512 + 32;
+    let total_sectors = bs.reserved_sectors + bs.sectors_per_fat;
+    device.borrow_mut().image[offset..offset + 4].copy_from_slice(&total_sectors.to_le_bytes());
+    assert_eq!(
+        code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).map(|_| ()),
+        Err(FatError::CorruptPartition as u8)
+    );
+
+    // FATs so big that their size doesn't fit in 32 bits
+    device.borrow_mut().image[offset + 4..offset + 8].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+    assert_eq!(
+        code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).map(|_| ()),
+        Err(FatError::CorruptPartition as u8)
+    );
+}
+
+#[test]
+fn open_volume_rejects_a_volume_bigger_than_its_partition() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    assert_eq!(bs.total_sectors, partition_entry.total_sectors());
+
+    let offset = partition_entry.start_sector() as usize * 512 + 32;
+    let total_sectors = bs.total_sectors + 1;
+    device.borrow_mut().image[offset..offset + 4].copy_from_slice(&total_sectors.to_le_bytes());
+    assert_eq!(
+        code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).map(|_| ()),
+        Err(FatError::CorruptPartition as u8)
+    );
+
+    // Smaller is fine; the rest of the partition just isn't used
+    let total_sectors = bs.total_sectors - 1;
+    device.borrow_mut().image[offset..offset + 4].copy_from_slice(&total_sectors.to_le_bytes());
+    code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry))
+        .map(|_| ())
+        .unwrap();
+}
+
+#[test]
+fn root_directory_can_start_anywhere() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    assert_eq!(bs.root_cluster, 2);
+
+    // Move the root directory to cluster 10, with a file in it, and put a decoy entry in the
+    // old root cluster so reading it by mistake shows up
+    let root_cluster = 10;
+    {
+        let image = &mut device.borrow_mut().image;
+        bs.set_fat_entry(image, 2, 0);
+        bs.set_fat_entry(image, root_cluster, FAT32_END_OF_CHAIN);
+        bs.set_fat_entry(image, 11, FAT32_END_OF_CHAIN);
+        let offset = partition_entry.start_sector() as usize * 512 + 44;
+        image[offset..offset + 4].copy_from_slice(&root_cluster.to_le_bytes());
+        bs.put_dir_entry(image, 2, 0, b"DECOY   TXT", ATTR_ARCHIVE, 11, 5);
+        bs.put_dir_entry(image, root_cluster, 0, b"HELLO   TXT", ATTR_ARCHIVE, 11, 5);
+        let data = bs.cluster_sector(11) as usize * 512;
+        image[data..data + 5].copy_from_slice(b"hello");
+    }
+
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+    let mut file = code(volume.open_by_name(&device, b"HELLO.TXT", O_RDONLY)).unwrap();
+    let mut buffer = [0u8; 8];
+    assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Ok(5));
+    assert_eq!(&buffer[..5], b"hello");
+    assert_eq!(
+        code(volume.open_by_name(&device, b"DECOY.TXT", O_RDONLY)).map(|_| ()),
+        Err(FatError::FileNotFound as u8)
+    );
+
+    let mut root = volume.open_root(O_RDONLY);
+    let mut entries = 0;
+    code(volume.ls(&device, &mut root, true, 0, 0, &mut entries, |_, _, entries| *entries += 1)).unwrap();
+    assert_eq!(entries, 1);
+}
+
+#[test]
+fn format_round_trip() {
+    let device = RamDisk::new(LARGE_DISK_SECTORS, LARGE_DISK_ERASE_BLOCK);
+
+    // Whatever was on the card before mustn't show through
+    device.borrow_mut().image.iter_mut().for_each(|b| *b = 0xa5);
+    let cache = SectorCache::<2>::new();
+    let primary = code(Mbr::write_fat32_part_info(&device, &cache)).unwrap();
+    assert!(primary[0].partition_type().is_fat32());
+    let table = code(PartitionTable::read(&device, &cache)).unwrap();
+    let partition_entry = code(table.partition(&device, &cache, 0)).unwrap().unwrap();
+    assert_eq!(partition_entry.start_sector(), LARGE_DISK_ERASE_BLOCK);
+
+    device.borrow_mut().writes.clear();
+    let volume = code(Volume::<2>::format(&device, cache, 0, &partition_entry, b"FIELDCARD  ", 0x1234abcd)).unwrap();
+    let free_space = volume.free_space();
+
+    let disk = device.borrow();
+    let image = &disk.image;
+    let start_sector = partition_entry.start_sector();
+    let bs = BootSector::read(image, start_sector);
+    assert_eq!(bs.hidden_sectors, start_sector);
+    assert_eq!(bs.total_sectors, partition_entry.total_sectors());
+    assert_eq!(bs.fat_count, 2);
+    assert_eq!(bs.root_cluster, 2);
+    assert_eq!(bs.fs_info_sector, 1);
+    assert_eq!(bs.backup_boot_sector, 6);
+    assert_eq!(bs.volume_serial, 0x1234abcd);
+    assert_eq!(&bs.volume_label, b"FIELDCARD  ");
+    assert!(bs.cluster_count() >= 65525);
+    assert!(bs.sectors_per_fat * 128 >= bs.cluster_count() + 2);
+
+    // The FATs and the data area start on erase block boundaries
+    assert_eq!(bs.fat_start_sector(0) % LARGE_DISK_ERASE_BLOCK, 0);
+    assert_eq!(bs.data_start_sector() % LARGE_DISK_ERASE_BLOCK, 0);
+
+    // Backups of the boot sector and FSInfo
+    let sector = |s: u32| &image[s as usize * 512..(s as usize + 1) * 512];
+    assert_eq!(sector(start_sector + 6), sector(start_sector));
+    assert_eq!(sector(start_sector + 7), sector(start_sector + 1));
+
+    // Both FATs only have the reserved entries and the root directory in them
+    let fat_bytes = bs.sectors_per_fat as usize * 512;
+    let fat0 = bs.fat_start_sector(0) as usize * 512;
+    let fat1 = bs.fat_start_sector(1) as usize * 512;
+    assert!(image[fat0..fat0 + fat_bytes] == image[fat1..fat1 + fat_bytes]);
+    assert_eq!(bs.fat_entry(image, 0, 0), 0x0ffffff8);
+    assert!(is_end_of_chain(bs.fat_entry(image, 0, 1)));
+    assert!(is_end_of_chain(bs.fat_entry(image, 0, bs.root_cluster)));
+    let free = bs.cluster_count() - 1;
+    assert_eq!(bs.free_clusters(image), free);
+    assert_eq!(bs.fs_info(image), (free, 2));
+
+    // The root directory is empty apart from the label
+    let root = bs.cluster_sector(bs.root_cluster) as usize * 512;
+    let root_bytes = bs.sectors_per_cluster as usize * 512;
+    assert_eq!(&image[root..root + 11], b"FIELDCARD  ");
+    assert_eq!(image[root + 11], ATTR_VOLUME_LABEL);
+    assert!(image[root + 32..root + root_bytes].iter().all(|b| *b == 0));
+
+    // Empty sectors go out several at a time rather than one write each
+    let fat_writes = disk.writes.iter().filter(|(s, _)| *s >= bs.fat_start_sector(0) && *s < bs.data_start_sector());
+    assert!(fat_writes.clone().any(|(_, count)| *count > 1));
+    assert!(fat_writes.count() < (2 * bs.sectors_per_fat / 4) as usize);
+    drop(disk);
+
+    // It mounts, both through the partition table and directly
+    let free_bytes = free as u64 * bs.sectors_per_cluster as u64 * 512;
+    assert_eq!(free_space, Some(free_bytes));
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+    assert_eq!(volume.free_space(), Some(free_bytes));
+    assert_eq!(code(volume.scan_free_space(&device)), Ok(free_bytes));
+    let volume = code(Volume::<2>::mount_first_fat(&device, 0)).unwrap();
+    assert_eq!(volume.total_space(), bs.cluster_count() as u64 * bs.sectors_per_cluster as u64 * 512);
+
+    let mut root = volume.open_root(O_RDONLY);
+    let mut entries = 0;
+    code(volume.ls(&device, &mut root, true, 0, 0, &mut entries, |_, _, entries| *entries += 1)).unwrap();
+    assert_eq!(entries, 1);
+}
+
+#[test]
+fn format_superfloppy_without_label() {
+    let device = RamDisk::new(SMALL_DISK_SECTORS, 1);
+    let partition_entry = PartitionEntry::Superfloppy(SMALL_DISK_SECTORS);
+    code(Volume::<1>::format(&device, SectorCache::new(), 0, &partition_entry, b"NO NAME    ", 1)).unwrap();
+
+    // Too small for 32 KiB clusters, so they shrink to fit
+    let bs = BootSector::read(&device.borrow().image, 0);
+    assert_eq!(bs.sectors_per_cluster, 1);
+    assert_eq!(bs.hidden_sectors, 0);
+    assert_eq!(&bs.volume_label, b"NO NAME    ");
+    let root = bs.cluster_sector(bs.root_cluster) as usize * 512;
+    assert_eq!(device.borrow().image[root], 0);
+
+    assert!(matches!(
+        code(PartitionTable::read(&device, &SectorCache::<1>::new())),
+        Ok(PartitionTable::Superfloppy(SMALL_DISK_SECTORS))
+    ));
+    code(Volume::<1>::mount_first_fat(&device, 0)).map(|_| ()).unwrap();
+
+    // Not even single-sector clusters make enough of them
+    let device = RamDisk::new(60000, 1);
+    let partition_entry = PartitionEntry::Superfloppy(60000);
+    assert_eq!(
+        code(Volume::<1>::format(&device, SectorCache::new(), 0, &partition_entry, b"NO NAME    ", 1)).map(|_| ()),
+        Err(FatError::UnsupportedVersion as u8)
+    );
+}
+
+// Put a file in the root directory whose clusters are out of order on the disk, filled with a
+// pattern that doesn't repeat every sector.  Its last cluster isn't full, and the bytes after the
+// end of the file are part of the pattern too.
+// returns: the file's clusters in order, and the contents of all of them
+fn put_fragmented_file(device: &RefCell<RamDisk>, bs: &BootSector) -> (Vec<u32>, Vec<u8>) {
+    let clusters = vec![5, 6, 9, 7];
+    let cluster_bytes = bs.sectors_per_cluster as usize * 512;
+    let contents: Vec<u8> = (0..clusters.len() * cluster_bytes).map(|i| (i % 251) as u8).collect();
+    let size = contents.len() as u32 - 100;
+
+    let image = &mut device.borrow_mut().image;
+    for (i, cluster) in clusters.iter().enumerate() {
+        let next = clusters.get(i + 1).copied().unwrap_or(FAT32_END_OF_CHAIN);
+        bs.set_fat_entry(image, *cluster, next);
+        let start = bs.cluster_sector(*cluster) as usize * 512;
+        image[start..start + cluster_bytes].copy_from_slice(&contents[i * cluster_bytes..(i + 1) * cluster_bytes]);
+    }
+    bs.put_dir_entry(image, bs.root_cluster, 0, b"FRAG    BIN", ATTR_ARCHIVE, clusters[0], size);
+    (clusters, contents)
+}
+
+#[test]
+fn read_a_fragmented_file() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(LARGE_DISK_SECTORS, LARGE_DISK_ERASE_BLOCK, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    assert!(bs.sectors_per_cluster > 1);
+    let (_, contents) = put_fragmented_file(&device, &bs);
+    let size = contents.len() - 100;
+
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+    let mut file = code(volume.open_by_name(&device, b"FRAG.BIN", O_RDONLY)).unwrap();
+    assert_eq!(file.size() as usize, size);
+
+    // All of it in one go, which has to follow the chain at every cluster boundary; whole
+    // sectors are read a cluster at a time, straight into the buffer
+    device.borrow_mut().reads.clear();
+    let mut buffer = vec![0u8; contents.len()];
+    assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Ok(size));
+    assert!(buffer[..size] == contents[..size]);
+    assert!(device.borrow().reads.iter().any(|(_, count)| *count == bs.sectors_per_cluster));
+
+    // Starting partway into a sector, the first part comes through the cache and the rest is
+    // still read in runs
+    code(volume.seek(&device, &mut file, 100)).unwrap();
+    buffer.iter_mut().for_each(|b| *b = 0);
+    assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Ok(size - 100));
+    assert!(buffer[..size - 100] == contents[100..size]);
+    assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Ok(0));
+}
+
+#[test]
+fn write_a_fragmented_file() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(LARGE_DISK_SECTORS, LARGE_DISK_ERASE_BLOCK, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let (clusters, contents) = put_fragmented_file(&device, &bs);
+    let size = contents.len() - 100;
+    let new_contents: Vec<u8> = contents.iter().map(|b| !b).collect();
+
+    // A few bytes through the cache, then the rest in one go: the rest of the first sector goes
+    // through the cache too, and whole sectors are written straight from the buffer
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+    let mut file = code(volume.open_by_name(&device, b"FRAG.BIN", O_RDWR)).unwrap();
+    device.borrow_mut().writes.clear();
+    assert_eq!(code(volume.write(&device, &mut file, &new_contents[..100])), Ok(100));
+    assert_eq!(code(volume.write(&device, &mut file, &new_contents[100..])), Ok(size - 100));
+    code(volume.sync(&device)).unwrap();
+    assert!(device.borrow().writes.iter().any(|(_, count)| *count == bs.sectors_per_cluster));
+
+    // Every cluster got its part of the file, and the bytes past the end were left alone
+    {
+        let image = &device.borrow().image;
+        let cluster_bytes = bs.sectors_per_cluster as usize * 512;
+        let mut on_disk = Vec::new();
+        for cluster in clusters.iter() {
+            let start = bs.cluster_sector(*cluster) as usize * 512;
+            on_disk.extend_from_slice(&image[start..start + cluster_bytes]);
+        }
+        assert!(on_disk[..size] == new_contents[..size]);
+        assert!(on_disk[size..] == contents[size..]);
+    }
+
+    // And it all reads back in one go
+    let mut file = code(volume.open_by_name(&device, b"FRAG.BIN", O_RDONLY)).unwrap();
+    let mut buffer = vec![0u8; contents.len()];
+    assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Ok(size));
+    assert!(buffer[..size] == new_contents[..size]);
+}
+
+#[test]
+fn reserved_fat_bits_are_ignored() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(LARGE_DISK_SECTORS, LARGE_DISK_ERASE_BLOCK, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let (clusters, contents) = put_fragmented_file(&device, &bs);
+    let size = contents.len() - 100;
+
+    // Only the bottom 28 bits of an entry are the link, and end of chain is anything from
+    // 0x0FFFFFF8 up once the top 4 are masked off
+    for (i, reserved) in [0xf000_0000, 0x5000_0000, 0xa000_0000].iter().enumerate() {
+        bs.set_fat_entry(&mut device.borrow_mut().image, clusters[i], reserved | clusters[i + 1]);
+    }
+    bs.set_fat_entry(&mut device.borrow_mut().image, clusters[3], 0xffff_fff8);
+
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+    let mut file = code(volume.open_by_name(&device, b"FRAG.BIN", O_RDONLY)).unwrap();
+    let mut buffer = vec![0u8; contents.len()];
+    assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Ok(size));
+    assert!(buffer[..size] == contents[..size]);
+
+    let cluster_bytes = bs.sectors_per_cluster as usize * 512;
+    code(volume.seek(&device, &mut file, (3 * cluster_bytes) as u32)).unwrap();
+    assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Ok(size - 3 * cluster_bytes));
+    assert!(buffer[..size - 3 * cluster_bytes] == contents[3 * cluster_bytes..size]);
+}
+
+#[test]
+fn bad_cluster_marker_is_not_the_end_of_a_chain() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(LARGE_DISK_SECTORS, LARGE_DISK_ERASE_BLOCK, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let (clusters, contents) = put_fragmented_file(&device, &bs);
+    let cluster_bytes = bs.sectors_per_cluster as usize * 512;
+    let mut buffer = vec![0u8; contents.len()];
+
+    // A chain that ends early is just shorter than its file
+    bs.set_fat_entry(&mut device.borrow_mut().image, clusters[1], 0x0fff_fff8);
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+    let mut file = code(volume.open_by_name(&device, b"FRAG.BIN", O_RDONLY)).unwrap();
+    assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Ok(2 * cluster_bytes));
+
+    // 0x0FFFFFF7 marks a bad cluster, which no chain should lead on to, with or without the
+    // reserved bits set
+    for bad in [0x0fff_fff7, 0xffff_fff7].iter() {
+        bs.set_fat_entry(&mut device.borrow_mut().image, clusters[1], *bad);
+        let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+        let mut file = code(volume.open_by_name(&device, b"FRAG.BIN", O_RDONLY)).unwrap();
+        assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Err(FatError::CorruptFat as u8));
+        let mut file = code(volume.open_by_name(&device, b"FRAG.BIN", O_RDONLY)).unwrap();
+        assert_eq!(
+            code(volume.seek(&device, &mut file, (2 * cluster_bytes + 1) as u32)),
+            Err(FatError::CorruptFat as u8)
+        );
+    }
+}
+
+#[test]
+fn seek_catches_a_looping_chain() {
+    let (device, partition_entry, _) = format_ram_disk::<2>(LARGE_DISK_SECTORS, LARGE_DISK_ERASE_BLOCK, b"NO NAME    ");
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    let (clusters, contents) = put_fragmented_file(&device, &bs);
+    let cluster_bytes = bs.sectors_per_cluster as usize * 512;
+
+    // 5 -> 6 -> 5 -> ..., far shorter than the volume, under a file that claims four clusters
+    bs.set_fat_entry(&mut device.borrow_mut().image, clusters[1], clusters[0]);
+    let mut volume = code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &partition_entry)).unwrap();
+    let mut file = code(volume.open_by_name(&device, b"FRAG.BIN", O_RDONLY)).unwrap();
+
+    // Going once round the loop doesn't show anything wrong yet
+    code(volume.seek(&device, &mut file, (cluster_bytes + 1) as u32)).unwrap();
+    code(volume.seek(&device, &mut file, 0)).unwrap();
+    assert_eq!(
+        code(volume.seek(&device, &mut file, (contents.len() - 100) as u32)),
+        Err(FatError::CorruptFat as u8)
+    );
+
+    // The file is left where it was
+    let mut buffer = vec![0u8; cluster_bytes];
+    assert_eq!(code(volume.read(&device, &mut file, &mut buffer)), Ok(cluster_bytes));
+    assert!(buffer == contents[..cluster_bytes]);
+}
+
+const GPT_DISK_SECTORS: u32 = 72200;
+
+fn gpt_partitions() -> Vec<GptPartition> {
+    vec![
+        GptPartition {
+            type_guid: basic_data_guid(),
+            unique_guid: guid(0x01020304, 0x0506, 0x0708, [9, 10, 11, 12, 13, 14, 15, 16]),
+            first_lba: 2048,
+            last_lba: 2048 + SMALL_DISK_SECTORS as u64 - 1,
+            name: "FIELD DATA",
+        },
+        GptPartition {
+            type_guid: linux_data_guid(),
+            unique_guid: guid(0x11121314, 0x1516, 0x1718, [19, 20, 21, 22, 23, 24, 25, 26]),
+            first_lba: 2048 + SMALL_DISK_SECTORS as u64,
+            last_lba: 2048 + SMALL_DISK_SECTORS as u64 + 99,
+            name: "linux",
+        },
+    ]
+}
+
+#[test]
+fn gpt_partition_mounts() {
+    let device = RamDisk::new(GPT_DISK_SECTORS, 1);
+    put_gpt(&device, &gpt_partitions());
+    let cache = SectorCache::<2>::new();
+    let table = code(PartitionTable::read(&device, &cache)).unwrap();
+    match &table {
+        PartitionTable::Gpt(gpt) => {
+            assert!(!gpt.is_backup);
+            assert!(gpt.disk_guid == Guid(gpt_disk_guid()));
+            assert_eq!(gpt.first_usable_sector, 34);
+            assert_eq!(gpt.last_usable_sector, GPT_DISK_SECTORS - 34);
+        },
+        _ => panic!("not read as a GPT"),
+    }
+    assert_eq!(table.len(), GPT_ENTRY_COUNT);
+
+    // GUIDs come back exactly as they're stored, and the name is UTF-16 padded with zeroes
+    let partition_entry = code(table.partition(&device, &cache, 0)).unwrap().unwrap();
+    match &partition_entry {
+        PartitionEntry::Gpt(info) => {
+            let expected = &gpt_partitions()[0];
+            assert!(info.type_guid == Guid(expected.type_guid));
+            assert!(info.unique_guid == Guid(expected.unique_guid));
+            assert_eq!(info.first_sector as u64, expected.first_lba);
+            assert_eq!(info.last_sector as u64, expected.last_lba);
+            let name: Vec<u16> = expected.name.encode_utf16().chain(core::iter::repeat(0)).take(36).collect();
+            assert_eq!(info.name.to_vec(), name);
+        },
+        _ => panic!("not a GPT entry"),
+    }
+    assert!(partition_entry.is_fat());
+    assert_eq!(partition_entry.start_sector(), 2048);
+    assert_eq!(partition_entry.total_sectors(), SMALL_DISK_SECTORS);
+
+    let linux = code(table.partition(&device, &cache, 1)).unwrap().unwrap();
+    assert!(!linux.is_fat());
+    assert!(code(table.partition(&device, &cache, 2)).unwrap().is_none());
+    assert_eq!(
+        code(table.partition(&device, &cache, GPT_ENTRY_COUNT)).map(|_| ()),
+        Err(FatError::BadPartitionNumber as u8)
+    );
+
+    code(Volume::<2>::format(&device, cache, 0, &partition_entry, b"NO NAME    ", 1)).unwrap();
+    let bs = BootSector::read(&device.borrow().image, 2048);
+    assert_eq!(bs.hidden_sectors, 2048);
+
+    // And again from scratch, the way a card would be mounted
+    let cache = SectorCache::<2>::new();
+    let table = code(PartitionTable::read(&device, &cache)).unwrap();
+    let partition_entry = code(table.partition(&device, &cache, 0)).unwrap().unwrap();
+    let volume = code(Volume::open_volume(&device, cache, 0, &partition_entry)).unwrap();
+    assert_eq!(volume.free_space(), Some((bs.cluster_count() as u64 - 1) * 512));
+    assert_eq!(
+        code(Volume::<2>::open_volume(&device, SectorCache::new(), 0, &linux)).map(|_| ()),
+        Err(FatError::NotFatPartition as u8)
+    );
+    code(Volume::<1>::mount_first_fat(&device, 0)).map(|_| ()).unwrap();
+}
+
+#[test]
+fn gpt_falls_back_to_the_backup_copy() {
+    let device = RamDisk::new(GPT_DISK_SECTORS, 1);
+    let read_first_partition = || {
+        let cache = SectorCache::<1>::new();
+        let gpt = match code(PartitionTable::read(&device, &cache)).unwrap() {
+            PartitionTable::Gpt(gpt) => gpt,
+            _ => panic!("not read as a GPT"),
+        };
+        let first_sector = match code(gpt.read_entry(&device, &cache, 0)).unwrap() {
+            Some(info) if info.type_guid == Guid(basic_data_guid()) => info.first_sector,
+            _ => panic!("first partition is missing"),
+        };
+        (gpt.is_backup, first_sector)
+    };
+
+    // Damage to the primary header shows up in its CRC
+    put_gpt(&device, &gpt_partitions());
+    assert_eq!(read_first_partition(), (false, 2048));
+    device.borrow_mut().image[512 + 60] ^= 0xff;
+    assert_eq!(read_first_partition(), (true, 2048));
+
+    // A good header with a damaged entry array is no use either, and the backup's own copy of
+    // the entries has to be the one that's used
+    put_gpt(&device, &gpt_partitions());
+    device.borrow_mut().image[2 * 512] ^= 0xff;
+    assert_eq!(read_first_partition(), (true, 2048));
+}
+
+#[test]
+fn gpt_with_both_copies_damaged_is_refused() {
+    let device = RamDisk::new(GPT_DISK_SECTORS, 1);
+    put_gpt(&device, &gpt_partitions());
+    device.borrow_mut().image[512 + 60] ^= 0xff;
+    device.borrow_mut().image[(GPT_DISK_SECTORS as usize - 1) * 512 + 60] ^= 0xff;
+    assert_eq!(
+        code(PartitionTable::read(&device, &SectorCache::<1>::new())).map(|_| ()),
+        Err(FatError::CorruptGPT as u8)
+    );
+    assert_eq!(code(Volume::<1>::mount_first_fat(&device, 0)).map(|_| ()), Err(FatError::CorruptGPT as u8));
+}
+
+#[test]
+fn gpt_with_a_huge_entry_array_is_refused() {
+    let device = RamDisk::new(GPT_DISK_SECTORS, 1);
+    put_gpt(&device, &gpt_partitions());
+
+    // 512 KiB of entries fits on the disk, but it's far more than any real table has, and
+    // checking its CRC would mean reading every one of them
+    {
+        let image = &mut device.borrow_mut().image;
+        let last_lba = GPT_DISK_SECTORS as u64 - 1;
+        put_gpt_header(image, 1, 2, 4096, 0);
+        put_gpt_header(image, last_lba, last_lba - 1024, 4096, 0);
+    }
+    device.borrow_mut().reads.clear();
+    assert_eq!(
+        code(PartitionTable::read(&device, &SectorCache::<1>::new())).map(|_| ()),
+        Err(FatError::CorruptGPT as u8)
+    );
+    assert!(device.borrow().reads.len() < 8);
+}
+
+// An MBR with a FAT32 partition and an extended partition holding three logical ones: a Linux
+// partition, a FAT32 one big enough to format and a small one at the end
+const EBR_DISK_SECTORS: u32 = 260000;
+const EXTENDED_START: u32 = 80000;
+const EXTENDED_SIZE: u32 = 170000;
+const EBR_OFFSETS: [u32; 3] = [0, 30000, 105000];
+const LOGICAL_PARTITIONS: [(u8, u32, u32); 3] =
+    [(PTYPE_LINUX, 2048, 20000), (PTYPE_FAT32_LBA, 2048, SMALL_DISK_SECTORS), (PTYPE_LINUX, 63, 10000)];
+
+fn put_extended_partition(device: &RefCell<RamDisk>) {
+    let image = &mut device.borrow_mut().image;
+    put_partition_entry(image, 0, 0, PTYPE_FAT32_LBA, 2048, 70000);
+    put_partition_entry(image, 0, 1, PTYPE_EXTENDED_LBA, EXTENDED_START, EXTENDED_SIZE);
+    for (i, (ptype, start, size)) in LOGICAL_PARTITIONS.iter().enumerate() {
+        let ebr_sector = EXTENDED_START + EBR_OFFSETS[i];
+        put_partition_entry(image, ebr_sector, 0, *ptype, *start, *size);
+        match EBR_OFFSETS.get(i + 1) {
+            Some(next) => put_partition_entry(image, ebr_sector, 1, PTYPE_EXTENDED_LBA, *next, 1),
+            None => put_partition_entry(image, ebr_sector, 1, 0, 0, 0),
+        }
+    }
+}
+
+// returns: how many logical partitions the table has
+fn logical_count(device: &RefCell<RamDisk>) -> u32 {
+    match code(PartitionTable::read(device, &SectorCache::<1>::new())).unwrap() {
+        PartitionTable::Mbr { logical_count, .. } => logical_count,
+        _ => panic!("not read as an MBR"),
+    }
+}
+
+#[test]
+fn logical_partitions_are_listed_and_mounted() {
+    let device = RamDisk::new(EBR_DISK_SECTORS, 1);
+    put_extended_partition(&device);
+    let cache = SectorCache::<2>::new();
+    let table = code(PartitionTable::read(&device, &cache)).unwrap();
+    assert_eq!(table.len(), 4 + 3);
+
+    // Logical partitions come after the four primary slots, with their start sectors made
+    // absolute
+    for (i, (ptype, start, size)) in LOGICAL_PARTITIONS.iter().enumerate() {
+        match code(table.partition(&device, &cache, 4 + i as u32)).unwrap() {
+            Some(PartitionEntry::Mbr(info)) => {
+                assert_eq!(info.ptype, *ptype);
+                assert_eq!({ info.start_sector }, EXTENDED_START + EBR_OFFSETS[i] + start);
+                assert_eq!({ info.total_sectors }, *size);
+            },
+            _ => panic!("logical partition {} is missing", i),
+        }
+    }
+    assert_eq!(code(table.partition(&device, &cache, 7)).map(|_| ()), Err(FatError::BadPartitionNumber as u8));
+
+    let partition_entry = code(table.partition(&device, &cache, 5)).unwrap().unwrap();
+    code(Volume::<2>::format(&device, cache, 5, &partition_entry, b"NO NAME    ", 1)).unwrap();
+
+    // The primary FAT32 partition was never formatted, so the logical one is the first that
+    // mounts
+    let volume = code(Volume::<2>::mount_first_fat(&device, 0)).unwrap();
+    let bs = BootSector::read(&device.borrow().image, partition_entry.start_sector());
+    assert_eq!(volume.total_space(), bs.cluster_count() as u64 * 512);
+}
+
+#[test]
+fn ebr_chain_that_loops_is_cut_short() {
+    let device = RamDisk::new(EBR_DISK_SECTORS, 1);
+    put_extended_partition(&device);
+    assert_eq!(logical_count(&device), 3);
+
+    // The second EBR links back to itself; the partitions before the bad link are still usable
+    let ebr_sector = EXTENDED_START + EBR_OFFSETS[1];
+    put_partition_entry(&mut device.borrow_mut().image, ebr_sector, 1, PTYPE_EXTENDED_LBA, EBR_OFFSETS[1], 1);
+    assert_eq!(logical_count(&device), 2);
+
+    // Back to the first EBR
+    put_partition_entry(&mut device.borrow_mut().image, ebr_sector, 1, PTYPE_EXTENDED_LBA, 0, 1);
+    assert_eq!(logical_count(&device), 2);
+}
+
+#[test]
+fn ebr_chain_that_leaves_the_extended_partition_is_cut_short() {
+    let device = RamDisk::new(EBR_DISK_SECTORS, 1);
+    put_extended_partition(&device);
+    let first_ebr = EXTENDED_START + EBR_OFFSETS[0];
+    let second_ebr = EXTENDED_START + EBR_OFFSETS[1];
+
+    // A link past the end of the extended partition, even though it's still on the disk
+    put_partition_entry(&mut device.borrow_mut().image, second_ebr, 1, PTYPE_EXTENDED_LBA, EXTENDED_SIZE, 1);
+    assert_eq!(logical_count(&device), 2);
+
+    // A link into the middle of the logical partition before it
+    put_extended_partition(&device);
+    put_partition_entry(&mut device.borrow_mut().image, first_ebr, 1, PTYPE_EXTENDED_LBA, 3000, 1);
+    assert_eq!(logical_count(&device), 1);
+
+    // A logical partition that runs past the end of the extended partition
+    put_extended_partition(&device);
+    let (ptype, start, _) = LOGICAL_PARTITIONS[2];
+    let size = EXTENDED_SIZE - EBR_OFFSETS[2] - start + 1;
+    put_partition_entry(&mut device.borrow_mut().image, EXTENDED_START + EBR_OFFSETS[2], 0, ptype, start, size);
+    assert_eq!(logical_count(&device), 2);
+
+    // And one that starts before the partition before it has finished
+    put_extended_partition(&device);
+    let size = EBR_OFFSETS[1] - LOGICAL_PARTITIONS[0].1 + 1;
+    put_partition_entry(&mut device.borrow_mut().image, first_ebr, 0, PTYPE_LINUX, LOGICAL_PARTITIONS[0].1, size);
+    assert_eq!(logical_count(&device), 1);
+}
+
+// Reading the table of a disk with a FAT32 partition and a Linux one behind it, after `damage`
+// has been done to the MBR
+fn read_damaged_mbr(damage: impl FnOnce(&mut [u8])) -> Result<[Option<PartitionEntry>; 4], u8> {
+    let device = RamDisk::new(SMALL_DISK_SECTORS, 1);
+    {
+        let image = &mut device.borrow_mut().image;
+        put_partition_entry(image, 0, 0, PTYPE_FAT32_LBA, 2048, 40000);
+        put_partition_entry(image, 0, 1, PTYPE_LINUX, 42048, SMALL_DISK_SECTORS - 42048);
+        damage(&mut image[..512]);
+    }
+    let cache = SectorCache::<1>::new();
+    let table = code(PartitionTable::read(&device, &cache))?;
+    assert_eq!(table.len(), 4);
+    let mut partitions = [None, None, None, None];
+    for (i, partition) in partitions.iter_mut().enumerate() {
+        *partition = code(table.partition(&device, &cache, i as u32)).unwrap();
+    }
+    Ok(partitions)
+}
+
+#[test]
+fn mbr_is_checked_when_read() {
+    let partitions = read_damaged_mbr(|_| ()).unwrap();
+    assert!(matches!(partitions, [Some(_), Some(_), None, None]));
+
+    let corrupt = Err(FatError::CorruptMBR as u8);
+    let set_entry = |mbr: &mut [u8], slot: usize, start_sector: u32, total_sectors: u32| {
+        mbr[446 + slot * 16 + 8..446 + slot * 16 + 12].copy_from_slice(&start_sector.to_le_bytes());
+        mbr[446 + slot * 16 + 12..446 + slot * 16 + 16].copy_from_slice(&total_sectors.to_le_bytes());
+    };
+    assert_eq!(read_damaged_mbr(|mbr| mbr[510..512].copy_from_slice(&[0, 0])).map(|_| ()), corrupt);
+
+    // Overlapping by a sector, then just touching
+    assert_eq!(read_damaged_mbr(|mbr| set_entry(mbr, 0, 2048, 40001)).map(|_| ()), corrupt);
+    assert!(read_damaged_mbr(|mbr| set_entry(mbr, 0, 2048, 40000)).is_ok());
+
+    // Running a sector past the end of the card, then just fitting
+    assert_eq!(read_damaged_mbr(|mbr| set_entry(mbr, 1, 42048, SMALL_DISK_SECTORS - 42047)).map(|_| ()), corrupt);
+    assert!(read_damaged_mbr(|mbr| set_entry(mbr, 1, 42048, SMALL_DISK_SECTORS - 42048)).is_ok());
+    assert_eq!(read_damaged_mbr(|mbr| set_entry(mbr, 1, 0, 100)).map(|_| ()), corrupt);
+}
+
+#[test]
+fn entry_with_a_bad_boot_flag_is_ignored() {
+    // Junk in an empty slot doesn't matter
+    let partitions = read_damaged_mbr(|mbr| mbr[446 + 2 * 16] = 0x12).unwrap();
+    assert!(matches!(partitions, [Some(_), Some(_), None, None]));
+
+    // and junk in a used one only loses that partition
+    let partitions = read_damaged_mbr(|mbr| mbr[446 + 16] = 0x12).unwrap();
+    assert!(matches!(partitions, [Some(_), None, None, None]));
+    let partitions = read_damaged_mbr(|mbr| mbr[446] = 0x80).unwrap();
+    assert!(matches!(partitions, [Some(_), Some(_), None, None]));
+}
+
+#[test]
+fn non_fat_partition_is_not_mounted() {
+    let (device, partition_entry, volume) = format_ram_disk::<1>(SMALL_DISK_SECTORS, 1, b"NO NAME    ");
+    code(volume.unmount(&device)).unwrap();
+
+    // The volume is still there, but the partition table says it's something else
+    device.borrow_mut().image[446 + 4] = PTYPE_LINUX;
+    let cache = SectorCache::<1>::new();
+    let table = code(PartitionTable::read(&device, &cache)).unwrap();
+    let linux_entry = code(table.partition(&device, &cache, 0)).unwrap().unwrap();
+    assert_eq!(linux_entry.start_sector(), partition_entry.start_sector());
+    let not_fat = Err(FatError::NotFatPartition as u8);
+    assert_eq!(code(Volume::<1>::open_volume(&device, cache, 0, &linux_entry)).map(|_| ()), not_fat);
+    assert_eq!(code(Volume::<1>::mount_first_fat(&device, 0)).map(|_| ()), not_fat);
+}
+
+#[test]
+fn partition_table_round_trip() {
+    // Big enough that the end of the card can't be given as a CHS address
+    let device = RamDisk::new(64, 1);
+    device.borrow_mut().claimed_sectors = Some(20_000_000);
+    device.borrow_mut().image[..446].iter_mut().for_each(|b| *b = 0x90);
+    device.borrow_mut().image[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    // The second partition ends on the last sector CHS can address and the third starts just past
+    // it
+    let entries = [
+        PartitionInfo::with_lba(PartitionType::Fat32Lba, 2048, 40000),
+        PartitionInfo::with_lba(PartitionType::Linux, 5_000_000, 16_450_560 - 5_000_000),
+        PartitionInfo::with_lba(PartitionType::Fat32Lba, 16_450_560, 1_000_000),
+    ];
+    let cache = SectorCache::<1>::new();
+    let written = code(Mbr::write_part_info(&device, &cache, &entries)).unwrap();
+
+    let expected: [[u8; 16]; 4] = [
+        [0x00, 0x20, 0x21, 0x00, 0x0c, 0x9d, 0x1b, 0x02, 0x00, 0x08, 0x00, 0x00, 0x40, 0x9c, 0x00, 0x00],
+        [0x00, 0x3c, 0x46, 0x37, 0x83, 0xfe, 0xff, 0xff, 0x40, 0x4b, 0x4c, 0x00, 0xc0, 0xb8, 0xae, 0x00],
+        [0x00, 0xfe, 0xff, 0xff, 0x0c, 0xfe, 0xff, 0xff, 0x00, 0x04, 0xfb, 0x00, 0x40, 0x42, 0x0f, 0x00],
+        [0; 16],
+    ];
+    {
+        let image = &device.borrow().image;
+        for (slot, entry) in expected.iter().enumerate() {
+            assert_eq!(&image[446 + slot * 16..446 + (slot + 1) * 16], entry, "slot {}", slot);
+        }
+        assert_eq!(&image[510..512], &[0x55, 0xaa]);
+        assert!(image[..446].iter().all(|b| *b == 0x90));
+    }
+
+    // What was written reads back the same, with nothing left over in the cache
+    let raw = |info: &PartitionInfo| {
+        let mut bytes = [info.boot, 0, 0, 0, info.ptype, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes[1..4].copy_from_slice(&info.begin_chs);
+        bytes[5..8].copy_from_slice(&info.end_chs);
+        bytes[8..12].copy_from_slice(&{ info.start_sector }.to_le_bytes());
+        bytes[12..16].copy_from_slice(&{ info.total_sectors }.to_le_bytes());
+        bytes
+    };
+    let read = code(Mbr::read_part_info(&device, &SectorCache::<1>::new())).unwrap();
+    for slot in 0..4 {
+        assert_eq!(raw(&written[slot]), expected[slot]);
+        assert_eq!(raw(&read[slot]), expected[slot]);
+    }
+    let table = code(PartitionTable::read(&device, &SectorCache::<1>::new())).unwrap();
+    assert_eq!(table.len(), 4);
+    for (slot, info) in entries.iter().enumerate() {
+        let partition_entry = code(table.partition(&device, &cache, slot as u32)).unwrap().unwrap();
+        assert_eq!(partition_entry.start_sector(), { info.start_sector });
+        assert_eq!(partition_entry.total_sectors(), { info.total_sectors });
+    }
+    assert!(code(table.partition(&device, &cache, 3)).unwrap().is_none());
+}
@@ -0,0 +1,222 @@
+// Runs the SD card driver against the simulated card; needs `cargo test --features sim`
+use sdfat32_rs::{
+    block_device::BlockDevice,
+    sdcard::{
+        sim::{
+            self,
+            Fault,
+            SimCard,
+            SimCardType,
+            SimCs,
+            SimSpi,
+        },
+        SdCard,
+        SdCardError,
+        BLOCK_SIZE,
+    },
+};
+
+const SIM_IMAGE_SECTORS: usize = 2048;
+
+const ALL_CARD_TYPES: [SimCardType; 4] = [SimCardType::Sdhc, SimCardType::Sdsc, SimCardType::SdV1, SimCardType::Mmc];
+
+// SdCardError doesn't implement Debug or PartialEq, so results are compared by error code
+fn code<T>(res: Result<T, SdCardError>) -> Result<T, u8> {
+    res.map_err(|e| e as u8)
+}
+
+// Every sector gets a different pattern, so reading the wrong one shows up
+fn test_image() -> Vec<u8> {
+    (0..SIM_IMAGE_SECTORS * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE) as u8 ^ i as u8).collect()
+}
+
+fn sector(image: &[u8], start: usize, count: usize) -> &[u8] {
+    &image[start * BLOCK_SIZE..(start + count) * BLOCK_SIZE]
+}
+
+fn init(card_type: SimCardType) -> (SimCard, SdCard<SimSpi, SimCs>) {
+    let card = SimCard::new(card_type, test_image());
+    let sdcard = match SdCard::new(card.spi(), card.cs(), sim::millis) {
+        Ok(sdcard) => sdcard.into_inner(),
+        Err(e) => panic!("initialization failed with error {}", e as u8),
+    };
+    (card, sdcard)
+}
+
+#[test]
+fn init_all_card_types() {
+    for card_type in ALL_CARD_TYPES.iter() {
+        let (card, mut sdcard) = init(*card_type);
+        assert!(card.is_fast_clock());
+
+        let csd = code(sdcard.read_card_specific_data()).unwrap();
+        assert_eq!(csd.sector_count(), SIM_IMAGE_SECTORS as u32);
+        assert_eq!(code(sdcard.sector_count()), Ok(SIM_IMAGE_SECTORS as u32));
+
+        let cid = code(sdcard.read_card_id()).unwrap();
+        assert_eq!(cid.product_name(), *b"SIM01");
+
+        // Only SD cards have the application-specific registers
+        if *card_type != SimCardType::Mmc {
+            assert_eq!(code(sdcard.read_scr()).unwrap().spec_version(), (3, 0));
+            assert_eq!(code(sdcard.read_sd_status()).unwrap().au_size_kib(), 4096);
+        }
+        assert!(!code(sdcard.status()).unwrap().idle());
+    }
+}
+
+#[test]
+fn read_single_and_multiple_blocks() {
+    let image = test_image();
+    for card_type in ALL_CARD_TYPES.iter() {
+        let (_card, mut sdcard) = init(*card_type);
+        let mut buf = vec![0u8; 5 * BLOCK_SIZE];
+
+        code(sdcard.read_sectors(3, &mut buf[..BLOCK_SIZE])).unwrap();
+        assert_eq!(&buf[..BLOCK_SIZE], sector(&image, 3, 1));
+
+        code(sdcard.read_sectors(7, &mut buf)).unwrap();
+        assert_eq!(&buf[..], sector(&image, 7, 5));
+
+        // The last sector on the card is readable, the one after it isn't
+        let last = SIM_IMAGE_SECTORS as u32 - 1;
+        code(sdcard.read_sectors(last, &mut buf[..BLOCK_SIZE])).unwrap();
+        assert_eq!(&buf[..BLOCK_SIZE], sector(&image, last as usize, 1));
+        assert!(sdcard.read_sectors(last + 1, &mut buf[..BLOCK_SIZE]).is_err());
+    }
+}
+
+#[test]
+fn write_single_and_multiple_blocks() {
+    for card_type in ALL_CARD_TYPES.iter() {
+        let (card, mut sdcard) = init(*card_type);
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i * 7) as u8).collect();
+
+        code(sdcard.write_sectors(20, &data[..BLOCK_SIZE])).unwrap();
+        code(sdcard.write_sectors(30, &data)).unwrap();
+        assert_eq!(sector(&card.image(), 20, 1), &data[..BLOCK_SIZE]);
+        assert_eq!(sector(&card.image(), 30, 3), &data[..]);
+
+        // Neighbouring sectors are left alone
+        let image = test_image();
+        assert_eq!(sector(&card.image(), 21, 1), sector(&image, 21, 1));
+        assert_eq!(sector(&card.image(), 33, 1), sector(&image, 33, 1));
+
+        // What was written reads back through the driver too
+        let mut buf = vec![0u8; 3 * BLOCK_SIZE];
+        code(sdcard.read_sectors(30, &mut buf)).unwrap();
+        assert_eq!(buf, data);
+    }
+}
+
+#[test]
+fn read_crc_error_is_retried() {
+    let image = test_image();
+    let (card, mut sdcard) = init(SimCardType::Sdhc);
+    let mut buf = vec![0u8; 5 * BLOCK_SIZE];
+
+    card.inject(Fault::ReadCrc);
+    code(sdcard.read_sectors(9, &mut buf[..BLOCK_SIZE])).unwrap();
+    assert_eq!(&buf[..BLOCK_SIZE], sector(&image, 9, 1));
+
+    card.inject(Fault::ReadCrc);
+    code(sdcard.read_sectors(7, &mut buf)).unwrap();
+    assert_eq!(&buf[..], sector(&image, 7, 5));
+
+    // With retries turned off the error comes back to the caller
+    sdcard.set_crc_retry_count(0);
+    card.inject(Fault::ReadCrc);
+    assert_eq!(code(sdcard.read_sectors(9, &mut buf[..BLOCK_SIZE])), Err(SdCardError::ReadCRCError as u8));
+}
+
+#[test]
+fn read_timeout() {
+    let (card, mut sdcard) = init(SimCardType::Sdhc);
+    let mut buf = vec![0u8; BLOCK_SIZE];
+
+    card.inject(Fault::ReadTimeout);
+    assert_eq!(code(sdcard.read_sectors(7, &mut buf)), Err(SdCardError::Timeout as u8));
+
+    // The card is fine afterwards
+    code(sdcard.read_sectors(7, &mut buf)).unwrap();
+    assert_eq!(&buf[..], sector(&test_image(), 7, 1));
+}
+
+#[test]
+fn illegal_command() {
+    let (card, mut sdcard) = init(SimCardType::Sdsc);
+    let mut buf = vec![0u8; BLOCK_SIZE];
+
+    card.inject(Fault::IllegalCommand(17));
+    assert_eq!(code(sdcard.read_sectors(1, &mut buf)), Err(SdCardError::IllegalCommand as u8));
+    code(sdcard.read_sectors(1, &mut buf)).unwrap();
+}
+
+#[test]
+fn no_response() {
+    let (card, mut sdcard) = init(SimCardType::SdV1);
+    let mut buf = vec![0u8; BLOCK_SIZE];
+
+    card.inject(Fault::NoResponse);
+    assert_eq!(code(sdcard.read_sectors(1, &mut buf)), Err(SdCardError::NoResponse as u8));
+    code(sdcard.read_sectors(1, &mut buf)).unwrap();
+
+    // A card that doesn't answer at all during initialization is reported too
+    let card = SimCard::new(SimCardType::Sdhc, test_image());
+    card.inject(Fault::NoResponse);
+    assert!(SdCard::new(card.spi(), card.cs(), sim::millis).is_err());
+}
+
+#[test]
+fn write_crc_error() {
+    let (card, mut sdcard) = init(SimCardType::Sdhc);
+    let data = vec![0xabu8; BLOCK_SIZE];
+
+    card.inject(Fault::WriteCrc);
+    assert_eq!(code(sdcard.write_sectors(40, &data)), Err(SdCardError::WriteCRCError as u8));
+    assert_eq!(sector(&card.image(), 40, 1), sector(&test_image(), 40, 1));
+}
+
+#[test]
+fn busy_card() {
+    let (card, mut sdcard) = init(SimCardType::Sdhc);
+    let data = vec![0xabu8; BLOCK_SIZE];
+    let mut buf = vec![0u8; BLOCK_SIZE];
+
+    // Busy for a while, but not for longer than a write is allowed to take
+    card.inject(Fault::Busy(200));
+    code(sdcard.write_sectors(40, &data)).unwrap();
+    assert_eq!(sector(&card.image(), 40, 1), &data[..]);
+
+    // A card that stays busy after a write times out, and so do the commands after it until the
+    // card finally comes back
+    card.inject(Fault::Busy(5000));
+    assert_eq!(code(sdcard.write_sectors(41, &data)), Err(SdCardError::Timeout as u8));
+    assert_eq!(code(sdcard.read_sectors(40, &mut buf)), Err(SdCardError::Timeout as u8));
+
+    let mut attempts = 0;
+    while sdcard.read_sectors(40, &mut buf).is_err() {
+        attempts += 1;
+        assert!(attempts < 10, "card never stopped being busy");
+    }
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn erase() {
+    for card_type in ALL_CARD_TYPES.iter() {
+        let (card, mut sdcard) = init(*card_type);
+
+        code(sdcard.erase(30, 31)).unwrap();
+        assert!(sector(&card.image(), 30, 2).iter().all(|b| *b == 0));
+        assert_eq!(sector(&card.image(), 32, 1), sector(&test_image(), 32, 1));
+
+        // An empty range is nothing to do
+        code(sdcard.erase_sectors(50, 0)).unwrap();
+        assert!(code(sdcard.erase(31, 30)).is_err());
+
+        // Ranges that run off the end of the sector numbers are refused rather than wrapping
+        assert_eq!(code(sdcard.erase(0, u32::MAX)), Err(SdCardError::AddressError as u8));
+        assert_eq!(code(sdcard.erase_sectors(u32::MAX - 1, 4)), Err(SdCardError::ParameterError as u8));
+    }
+}